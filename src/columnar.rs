@@ -0,0 +1,288 @@
+use crate::common::{AllData, FailureRecord, SystemInfo};
+use crate::utils::save_json;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// Version of the layout below, bumped on incompatible changes
+pub const COLUMNAR_FORMAT_VERSION: u32 = 1;
+
+/// `manifest.json` of a columnar export. Commits, machines and benchmarks are interned:
+/// series and shards refer to them by their index in these tables.
+#[derive(Debug, Serialize)]
+pub struct ColumnarManifest {
+    pub format_version: u32,
+    /// Sorted by hash, order points along `git-graph.json` for history views
+    pub commits: Vec<String>,
+    pub machines: Vec<String>,
+    pub benchmarks: Vec<String>,
+    /// Latest system info of each machine, aligned with `machines`. Null for machines that
+    /// only have failures.
+    pub systems: Vec<Option<SystemInfo>>,
+    pub failures: Vec<FailureEntry>,
+    /// File holding every series, relative to the manifest
+    pub all: String,
+    pub shards: Vec<ShardInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailureEntry {
+    pub commit: u32,
+    pub machine: u32,
+    pub record: FailureRecord,
+}
+
+/// A file holding the series of a single benchmark
+#[derive(Debug, Serialize)]
+pub struct ShardInfo {
+    pub benchmark: u32,
+    /// Relative to the manifest. Named after the index rather than the id, which can hold `/`
+    /// or characters file systems reject.
+    pub path: String,
+    /// Number of data points over all machines
+    pub points: usize,
+}
+
+/// Content of `all.json` and of each shard
+#[derive(Debug, Serialize)]
+pub struct SeriesFile<'a> {
+    pub series: &'a [Series],
+}
+
+/// One benchmark on one machine. All columns are aligned with `commits`.
+#[derive(Debug, Serialize)]
+pub struct Series {
+    pub benchmark: u32,
+    pub machine: u32,
+    pub unit: String,
+    pub commits: Vec<u32>,
+    pub estimate: Vec<f64>,
+    pub lower_bound: Vec<f64>,
+    pub upper_bound: Vec<f64>,
+    /// Elements/bytes per second, only present if some point declares a throughput
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_second: Option<Vec<Option<f64>>>,
+    /// Standard deviation across `bench --repeat` runs, only present if some point was repeated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_std_dev: Option<Vec<Option<f64>>>,
+}
+
+/// Convert the scanned db into interned tables and one series per (benchmark, machine)
+pub fn build(all_data: &AllData) -> (ColumnarManifest, Vec<Series>) {
+    let commits: Vec<String> = all_data
+        .commits
+        .keys()
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let machines: Vec<String> = all_data
+        .machines
+        .keys()
+        .chain(
+            all_data
+                .commits
+                .values()
+                .flat_map(|data| data.failures.keys()),
+        )
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let benchmarks: Vec<String> = all_data
+        .commits
+        .values()
+        .flat_map(|data| data.benchmarks.values())
+        .flat_map(|results| results.keys().cloned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let index = |table: &[String], key: &str| table.binary_search_by(|k| k.as_str().cmp(key)).ok();
+
+    let mut series: BTreeMap<(u32, u32), Series> = BTreeMap::new();
+    let mut failures = Vec::new();
+    for (commit_idx, commit) in commits.iter().enumerate() {
+        let data = &all_data.commits[commit];
+        for (machine, results) in &data.benchmarks {
+            let Some(machine_idx) = index(&machines, machine) else {
+                continue;
+            };
+            for (bench_id, value) in results {
+                let bench_idx = index(&benchmarks, bench_id).unwrap() as u32;
+                let s = series
+                    .entry((bench_idx, machine_idx as u32))
+                    .or_insert_with(|| Series {
+                        benchmark: bench_idx,
+                        machine: machine_idx as u32,
+                        unit: value.unit.clone(),
+                        commits: Vec::new(),
+                        estimate: Vec::new(),
+                        lower_bound: Vec::new(),
+                        upper_bound: Vec::new(),
+                        per_second: None,
+                        repeat_std_dev: None,
+                    });
+                let len = s.commits.len();
+                s.commits.push(commit_idx as u32);
+                s.estimate.push(value.estimate);
+                s.lower_bound.push(value.lower_bound);
+                s.upper_bound.push(value.upper_bound);
+                push_optional(
+                    &mut s.per_second,
+                    len,
+                    value.throughput.as_ref().map(|t| t.per_second),
+                );
+                push_optional(
+                    &mut s.repeat_std_dev,
+                    len,
+                    value.repeats.as_ref().map(|r| r.std_dev),
+                );
+            }
+        }
+        for (machine, record) in &data.failures {
+            if let Some(machine_idx) = index(&machines, machine) {
+                failures.push(FailureEntry {
+                    commit: commit_idx as u32,
+                    machine: machine_idx as u32,
+                    record: record.clone(),
+                });
+            }
+        }
+    }
+    let series: Vec<Series> = series.into_values().collect();
+
+    let shards = benchmarks
+        .iter()
+        .enumerate()
+        .map(|(bench_idx, _)| ShardInfo {
+            benchmark: bench_idx as u32,
+            path: format!("shards/{}.json", bench_idx),
+            points: series
+                .iter()
+                .filter(|s| s.benchmark == bench_idx as u32)
+                .map(|s| s.commits.len())
+                .sum(),
+        })
+        .collect();
+    let manifest = ColumnarManifest {
+        format_version: COLUMNAR_FORMAT_VERSION,
+        systems: machines
+            .iter()
+            .map(|m| all_data.machines.get(m).cloned())
+            .collect(),
+        commits,
+        machines,
+        benchmarks,
+        failures,
+        all: "all.json".to_string(),
+        shards,
+    };
+    (manifest, series)
+}
+
+/// Push `value` onto an optional column of which `len` entries are already known
+fn push_optional(column: &mut Option<Vec<Option<f64>>>, len: usize, value: Option<f64>) {
+    match (column.as_mut(), value) {
+        (Some(column), value) => column.push(value),
+        (None, None) => {}
+        (None, Some(value)) => {
+            let mut values = vec![None; len];
+            values.push(Some(value));
+            *column = Some(values);
+        }
+    }
+}
+
+/// Write the columnar export of `all_data` into `out_dir`, replacing any previous export
+pub fn write(all_data: &AllData, out_dir: &Path) -> Result<ColumnarManifest> {
+    let (manifest, series) = build(all_data);
+
+    if out_dir.exists() {
+        std::fs::remove_dir_all(out_dir)
+            .with_context(|| format!("failed to remove {}", out_dir.display()))?;
+    }
+    save_json(
+        out_dir.join(&manifest.all),
+        &SeriesFile { series: &series },
+        false,
+    )?;
+    for shard in &manifest.shards {
+        let start = series.partition_point(|s| s.benchmark < shard.benchmark);
+        let end = series.partition_point(|s| s.benchmark <= shard.benchmark);
+        save_json(
+            out_dir.join(&shard.path),
+            &SeriesFile {
+                series: &series[start..end],
+            },
+            false,
+        )?;
+    }
+    save_json(out_dir.join("manifest.json"), &manifest, false)?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{BenchValue, CommitBenchData};
+    use crate::test_utils::system;
+    use std::collections::HashMap;
+
+    fn commit(results: &[(&str, &str, f64)]) -> CommitBenchData {
+        let mut data = CommitBenchData::default();
+        for &(machine, bench_id, estimate) in results {
+            if !data.machines.iter().any(|m| m == machine) {
+                data.machines.push(machine.to_string());
+            }
+            data.benchmarks
+                .entry(machine.to_string())
+                .or_insert_with(HashMap::new)
+                .insert(bench_id.to_string(), BenchValue::exact(estimate, "ns"));
+        }
+        data
+    }
+
+    #[test]
+    fn interns_and_aligns_series() {
+        let mut all_data = AllData::default();
+        all_data.commits.insert(
+            "b".repeat(40),
+            commit(&[("lab", "render/a", 2.0), ("lab", "../eval", 3.0)]),
+        );
+        all_data
+            .commits
+            .insert("a".repeat(40), commit(&[("lab", "render/a", 1.0)]));
+        all_data
+            .machines
+            .insert("lab".to_string(), serde_json::from_value(system()).unwrap());
+
+        let (manifest, series) = build(&all_data);
+        assert_eq!(manifest.commits, ["a".repeat(40), "b".repeat(40)]);
+        assert_eq!(manifest.benchmarks, ["../eval", "render/a"]);
+        assert_eq!(manifest.machines, ["lab"]);
+        assert!(manifest.systems[0].is_some());
+
+        assert_eq!(series.len(), 2);
+        let render = &series[1];
+        assert_eq!(render.benchmark, 1);
+        assert_eq!(render.commits, [0, 1]);
+        assert_eq!(render.estimate, [1.0, 2.0]);
+        assert!(render.per_second.is_none());
+
+        // Shards stay inside `shards/` whatever the benchmark id
+        let paths: Vec<&str> = manifest.shards.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(paths, ["shards/0.json", "shards/1.json"]);
+        assert_eq!(manifest.shards[1].points, 2);
+    }
+
+    #[test]
+    fn optional_columns_are_backfilled() {
+        let mut column = None;
+        push_optional(&mut column, 0, None);
+        assert!(column.is_none());
+        push_optional(&mut column, 1, Some(2.0));
+        push_optional(&mut column, 2, None);
+        assert_eq!(column, Some(vec![None, Some(2.0), None]));
+    }
+}
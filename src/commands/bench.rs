@@ -1,170 +1,515 @@
-use crate::common::{BenchmarkEvent, RunManifest};
-use crate::utils::{collect_system_info, run_git, save_json};
+use crate::common::{
+    AdapterSelector, BenchRecord, BuildInfo, BuildMetrics, FailureRecord, FailureStage,
+    RunManifest, SystemInfo, METRICS_FILE, REPEATS_DIR,
+};
+use crate::config::Workspace;
+use crate::harness::{
+    build, terminate_running, CargoCommand, CompletedBench, HarnessKind, StageError,
+};
+use crate::migrations::{load_manifest, CURRENT_FORMAT_VERSION};
+use crate::utils::{collect_system_info, copy_dir_all, run_git, save_json};
 use anyhow::{anyhow, Context, Result};
-use indicatif::ProgressStyle;
-use std::io::{BufRead, BufReader};
-use std::ops::{Deref, DerefMut};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
-use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tracing::{info, warn};
-use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-struct ChildGuard(Child);
+/// Write `failure.json` into `run_dir`. Errors that aren't a [`StageError`] are
+/// recorded as failing in `stage`.
+pub fn record_failure(
+    run_dir: &Path,
+    commit_hash: &str,
+    name: &str,
+    stage: FailureStage,
+    err: &anyhow::Error,
+) -> Result<()> {
+    let stage_err = err.downcast_ref::<StageError>();
+    let record = FailureRecord {
+        commit_hash: commit_hash.to_string(),
+        name: name.to_string(),
+        stage: stage_err.map_or(stage, |e| e.stage),
+        exit_code: stage_err.and_then(|e| e.exit_code),
+        error: format!("{err:#}"),
+        stderr_tail: stage_err.map(|e| e.stderr_tail.clone()).unwrap_or_default(),
+        timestamp: chrono::Local::now().to_rfc3339(),
+        build: stage_err.and_then(|e| e.build.clone()),
+    };
+    let path = run_dir.join("failure.json");
+    save_json(&path, &record, true)?;
+    warn!("failure recorded to {}", path.display());
+    Ok(())
+}
 
-impl Drop for ChildGuard {
-    fn drop(&mut self) {
-        let _ = self.0.kill();
-    }
+/// Restricts a command to a subset of the benchmarks
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct BenchFilter {
+    /// Only the benchmarks whose ID matches this regex
+    #[arg(long)]
+    pub filter: Option<String>,
+    /// Only the benchmarks of this group (e.g. "render")
+    #[arg(long)]
+    pub group: Option<String>,
 }
 
-impl Deref for ChildGuard {
-    type Target = Child;
+impl BenchFilter {
+    pub fn is_empty(&self) -> bool {
+        self.filter.is_none() && self.group.is_none()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// The regex matching the selected benchmark IDs, combining `group` and `filter`. It's
+    /// also what criterion gets to skip the other benchmarks.
+    pub fn criterion_filter(&self) -> Option<String> {
+        match (&self.group, &self.filter) {
+            (Some(group), Some(filter)) => {
+                Some(format!("^{}/.*(?:{})", regex::escape(group), filter))
+            }
+            (Some(group), None) => Some(format!("^{}/", regex::escape(group))),
+            (None, Some(filter)) => Some(filter.clone()),
+            (None, None) => None,
+        }
     }
 }
 
-impl DerefMut for ChildGuard {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
+#[derive(Clone)]
+pub struct BenchOptions {
+    /// Overwrite an existing run
+    pub force: bool,
+    /// Merge into an existing run instead of replacing it (implied by a filter)
+    pub merge: bool,
+    pub adapter: Option<AdapterSelector>,
+    pub filter: BenchFilter,
+    /// How many times the suite is run, see [`REPEATS_DIR`]
+    pub repeat: usize,
+    pub harness: HarnessKind,
+    /// Program run instead of `cargo` by the harness
+    pub cargo: CargoCommand,
+    /// Environment of the benchmark process
+    pub env: Vec<(String, String)>,
+    /// Set by a signal handler, a run stopped by it isn't recorded as a failure
+    pub interrupted: Option<Arc<AtomicBool>>,
 }
 
-pub fn run(repo_dir: &Path, name: &str, force: bool) -> Result<()> {
-    let benches_dir = repo_dir.join("benches");
-    let commit_hash = run_git(repo_dir, ["rev-parse", "HEAD"])?.trim().to_string();
-
-    let root_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let db_root = root_dir.join("db");
-    let run_dir = db_root.join(&commit_hash).join(name);
-    let tmp_dir = db_root.join(&commit_hash).join(format!("{}.tmp", name));
-
-    info!("benchmark output will be saved to {}", run_dir.display());
-    if run_dir.exists() {
-        if !force {
-            return Err(anyhow!(
-                "output directory already exists, use --force to overwrite"
-            ));
-        }
-        warn!("output directory already exists, will overwrite on success");
-    }
+/// Handle Ctrl-C, SIGTERM and SIGHUP by terminating the harness running, so a run stops
+/// right away instead of finishing its suite before the caller cleans up. Returns the flag
+/// the handler sets, see [`BenchOptions::interrupted`].
+pub fn handle_interrupts() -> Result<Arc<AtomicBool>> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    ctrlc::set_handler(move || {
+        flag.store(true, Ordering::SeqCst);
+        terminate_running();
+    })
+    .context("failed to set Ctrl-C handler")?;
+    Ok(interrupted)
+}
 
-    // Clean up any leftover tmp dir
-    if tmp_dir.exists() {
-        std::fs::remove_dir_all(&tmp_dir).context("failed to remove leftover tmp directory")?;
-    }
-    std::fs::create_dir_all(&tmp_dir)?;
+/// Run the benchmarks of the checked out commit into `db/<hash>/<name>`.
+///
+/// With `merge` (implied by a filter), the results are merged into the existing run of the
+/// commit: only the completed benchmarks overwrite their files, the others are kept.
+pub fn run(workspace: &Workspace, name: &str, options: &BenchOptions) -> Result<()> {
+    let BenchOptions {
+        force,
+        merge,
+        ref adapter,
+        ref filter,
+        repeat,
+        harness,
+        ref cargo,
+        ref env,
+        ref interrupted,
+    } = *options;
+    let adapter = adapter.as_ref();
+    let repeat = repeat.max(1);
+    let commit_hash = run_git(&workspace.repo_dir, ["rev-parse", "HEAD"])?
+        .trim()
+        .to_string();
 
-    let system_info = collect_system_info();
+    // Before staging anything, an adapter that can't be found is a usage error
+    let system_info = collect_system_info(adapter)?;
+    let staging = RunStaging::prepare(
+        &workspace.db_root,
+        &commit_hash,
+        name,
+        force,
+        merge || !filter.is_empty(),
+    )?;
 
-    info!("running criterion benchmark...");
-    let result = run_benchmarks(&benches_dir, &tmp_dir);
+    // Only pin the adapter of the benchmark process when one was explicitly selected
+    let mut envs = env.clone();
+    if adapter.is_some() {
+        envs.extend(adapter_env(&system_info));
+    }
 
-    match result {
-        Ok(bench_ids) => {
-            // Save RunManifest into tmp dir
-            let run_manifest = RunManifest {
-                commit_hash: commit_hash.clone(),
-                name: name.to_string(),
-                system: system_info,
-                benchmarks: bench_ids,
-            };
-            save_json(tmp_dir.join("run.json"), &run_manifest)?;
+    info!("using the {:?} harness", harness);
+    let runner = harness.harness();
+    info!("building benchmarks...");
+    let result = build(cargo, &workspace.benches_dir, &envs).and_then(|build| {
+        info!(
+            "built in {:.1}s ({} crates compiled, {} warnings)",
+            build.0.duration_secs, build.0.compiled, build.0.warnings
+        );
+        (0..repeat)
+            .map(|i| {
+                if i > 0 {
+                    info!("running repetition {}/{}...", i + 1, repeat);
+                }
+                let output_dir = repetition_dir(&staging.tmp_dir, i);
+                info!("running benchmarks...");
+                runner.run(
+                    cargo,
+                    &workspace.benches_dir,
+                    &output_dir,
+                    &envs,
+                    filter.criterion_filter().as_deref(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()
+            // Only keep benchmarks completed in every repetition
+            .and_then(|mut runs| {
+                let first = runs.remove(0);
+                let (completed, dropped): (Vec<_>, Vec<_>) = first
+                    .into_iter()
+                    .partition(|bench| runs.iter().all(|run| run.iter().any(|b| b.id == bench.id)));
+                // Also the ones missing from the first repetition
+                let mut dropped: Vec<&str> = dropped
+                    .iter()
+                    .chain(runs.iter().flatten())
+                    .map(|bench| bench.id.as_str())
+                    .filter(|&id| !completed.iter().any(|bench| bench.id == id))
+                    .collect();
+                dropped.sort();
+                dropped.dedup();
+                if !dropped.is_empty() {
+                    warn!("dropping {:?}, not completed in every repetition", dropped);
+                    staging.discard(&dropped)?;
+                }
+                Ok(completed)
+            })
+            .map(|completed| (build, completed))
+    });
 
-            // Atomically move tmp -> final
-            if run_dir.exists() {
-                std::fs::remove_dir_all(&run_dir)
-                    .context("failed to remove existing output directory")?;
-            }
-            std::fs::rename(&tmp_dir, &run_dir).context("failed to move tmp dir to final")?;
-            info!("benchmark results saved to {}", run_dir.display());
-            Ok(())
+    match result {
+        Ok((build, completed)) => {
+            staging.finish(system_info, completed, repeat, Some(harness), Some(build))
+        }
+        Err(e)
+            if interrupted
+                .as_ref()
+                .is_some_and(|i| i.load(Ordering::SeqCst)) =>
+        {
+            warn!("benchmark interrupted, cleaning up tmp directory");
+            staging.abort();
+            Err(e)
         }
         Err(e) => {
-            // Clean up tmp dir on failure
             warn!("benchmark failed, cleaning up tmp directory");
-            let _ = std::fs::remove_dir_all(&tmp_dir);
+            let run_dir = staging.abort();
+            record_failure(&run_dir, &commit_hash, name, FailureStage::Bench, &e)?;
             Err(e)
         }
     }
 }
 
-fn run_benchmarks(benches_dir: &Path, output_dir: &Path) -> Result<Vec<String>> {
-    let child = Command::new("cargo")
-        .current_dir(benches_dir)
-        .arg("criterion")
-        .arg("--message-format=json")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("failed to spawn cargo criterion")?;
-    let mut child = ChildGuard(child);
-
-    let stdout = child.stdout.take().unwrap();
-    let mut stdout = BufReader::new(stdout);
-
-    // Parse cargo/criterion stderr to drive a spinner showing current benchmark status
-    let stderr = child.stderr.take().unwrap();
-    thread::spawn(move || {
-        let reader = BufReader::new(stderr);
-        let spinner_span = tracing::info_span!("criterion");
-        spinner_span.pb_set_style(&ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
-        let _guard = spinner_span.enter();
-
-        for line in reader.lines() {
-            match line {
-                Ok(line) if line.is_empty() => {}
-                Ok(line) => {
-                    // Criterion outputs lines like "Benchmarking eval/eval_static_squares/10: Warming up for 3.0000 s"
-                    if line.starts_with("Benchmarking ") {
-                        spinner_span.pb_set_message(&line);
-                    } else {
-                        info!(target: "cargo", "{}", line);
-                    }
-                }
-                _ => {}
+/// A run being written to `db/<hash>/<name>.tmp`, atomically moved over
+/// `db/<hash>/<name>` once complete
+pub struct RunStaging {
+    commit_hash: String,
+    name: String,
+    pub run_dir: PathBuf,
+    pub tmp_dir: PathBuf,
+    /// The existing run the results are merged into
+    existing: Option<RunManifest>,
+}
+
+impl RunStaging {
+    /// Check the existing run of the commit and set up the tmp dir. With `merge`, the tmp
+    /// dir starts from the existing results so only the new benchmarks overwrite theirs.
+    pub fn prepare(
+        db_root: &Path,
+        commit_hash: &str,
+        name: &str,
+        force: bool,
+        merge: bool,
+    ) -> Result<Self> {
+        let run_dir = db_root.join(commit_hash).join(name);
+        let tmp_dir = db_root.join(commit_hash).join(format!("{}.tmp", name));
+
+        info!("benchmark output will be saved to {}", run_dir.display());
+        // A directory holding only a failure.json is not a result worth protecting
+        let existing: Option<RunManifest> = if run_dir.join("run.json").exists() {
+            load_manifest(&run_dir).ok()
+        } else {
+            None
+        };
+        let merge = merge && existing.is_some();
+        if merge {
+            info!("output directory already exists, will merge the new results into it");
+        } else if existing.is_some() {
+            if !force {
+                return Err(anyhow!(
+                    "output directory already exists, use --force to overwrite"
+                ));
             }
+            warn!("output directory already exists, will overwrite on success");
         }
-    });
 
-    let mut buf = String::new();
-    let mut bench_ids = Vec::new();
+        // Clean up any leftover tmp dir
+        if tmp_dir.exists() {
+            std::fs::remove_dir_all(&tmp_dir).context("failed to remove leftover tmp directory")?;
+        }
+        if merge {
+            // Start from the existing results so the rename below stays atomic
+            copy_dir_all(&run_dir, &tmp_dir, &["run.json", "failure.json"])
+                .context("failed to copy existing results to tmp directory")?;
+        } else {
+            std::fs::create_dir_all(&tmp_dir)?;
+        }
+
+        Ok(RunStaging {
+            commit_hash: commit_hash.to_string(),
+            name: name.to_string(),
+            run_dir,
+            tmp_dir,
+            existing: existing.filter(|_| merge),
+        })
+    }
 
-    while let Ok(len) = stdout.read_line(&mut buf) {
-        if len == 0 {
-            break;
+    /// Record `completed` in the run's manifest and move the tmp dir into place
+    pub fn finish(
+        self,
+        system_info: SystemInfo,
+        completed: Vec<CompletedBench>,
+        repeats: usize,
+        harness: Option<HarnessKind>,
+        build: Option<(BuildInfo, BuildMetrics)>,
+    ) -> Result<()> {
+        let mut run_manifest = match self.existing {
+            Some(existing) => existing,
+            None => RunManifest {
+                format_version: CURRENT_FORMAT_VERSION,
+                commit_hash: self.commit_hash,
+                name: self.name,
+                system: system_info.clone(),
+                benchmarks: Vec::new(),
+                records: BTreeMap::new(),
+                systems: Vec::new(),
+                build: None,
+            },
+        };
+        // A top-up merged into the run builds the same commit again, mostly from the cache:
+        // the build of the run it's merged into is the one worth keeping
+        if let Some((info, metrics)) = build
+            && run_manifest.build.is_none()
+        {
+            run_manifest.build = Some(info);
+            save_json(self.tmp_dir.join(METRICS_FILE), &metrics, true)?;
         }
-        if let Ok(event) = serde_json::from_str::<BenchmarkEvent>(&buf) {
-            match event {
-                BenchmarkEvent::BenchmarkComplete(evt) => {
-                    info!("benchmark `{}` complete.", evt.id);
-                    save_json(output_dir.join(&evt.id).with_extension("json"), &evt.data)?;
-                    bench_ids.push(evt.id);
-                }
-                BenchmarkEvent::GroupComplete(evt) => {
-                    info!(
-                        "benchmark group `{} {:?}` complete.",
-                        evt.group_name, evt.benchmarks
-                    );
-                    save_json(
-                        output_dir
-                            .join(&evt.group_name)
-                            .join("group")
-                            .with_extension("json"),
-                        &evt,
-                    )?;
+        run_manifest.system = system_info.clone();
+        run_manifest.systems.push(system_info);
+        let system = run_manifest.systems.len() - 1;
+        // A merged run may hold more repetitions of the completed benchmarks than were just run
+        let stale: Vec<usize> = repetitions(&self.tmp_dir)?
+            .into_iter()
+            .filter(|&i| i >= repeats)
+            .collect();
+        for bench in completed {
+            for &i in &stale {
+                remove_file_if_exists(&bench_file(&repetition_dir(&self.tmp_dir, i), &bench.id))?;
+            }
+            if !run_manifest.benchmarks.contains(&bench.id) {
+                run_manifest.benchmarks.push(bench.id.clone());
+            }
+            run_manifest.records.insert(
+                bench.id,
+                BenchRecord {
+                    timestamp: Some(bench.timestamp),
+                    system,
+                    repeats,
+                    harness,
+                },
+            );
+        }
+
+        // Save RunManifest into tmp dir
+        save_json(self.tmp_dir.join("run.json"), &run_manifest, true)?;
+
+        // Atomically move tmp -> final
+        if self.run_dir.exists() {
+            std::fs::remove_dir_all(&self.run_dir)
+                .context("failed to remove existing output directory")?;
+        }
+        std::fs::rename(&self.tmp_dir, &self.run_dir).context("failed to move tmp dir to final")?;
+        info!("benchmark results saved to {}", self.run_dir.display());
+        Ok(())
+    }
+
+    /// Remove the files of the benchmarks `ids` from the tmp dir, putting back the ones of the
+    /// existing run when merging into it
+    pub fn discard(&self, ids: &[&str]) -> Result<()> {
+        for &id in ids {
+            for i in repetitions(&self.tmp_dir)? {
+                remove_file_if_exists(&bench_file(&repetition_dir(&self.tmp_dir, i), id))?;
+            }
+            let Some(existing) = &self.existing else {
+                continue;
+            };
+            let repeats = existing.records.get(id).map_or(1, |record| record.repeats);
+            for i in 0..repeats {
+                let src = bench_file(&repetition_dir(&self.run_dir, i), id);
+                if src.exists() {
+                    let dst = bench_file(&repetition_dir(&self.tmp_dir, i), id);
+                    std::fs::create_dir_all(dst.parent().unwrap())?;
+                    std::fs::copy(&src, &dst)
+                        .with_context(|| format!("failed to restore {}", src.display()))?;
                 }
             }
         }
-        buf.clear();
+        Ok(())
+    }
+
+    /// Remove the tmp dir, returning the run dir
+    pub fn abort(self) -> PathBuf {
+        let _ = std::fs::remove_dir_all(&self.tmp_dir);
+        self.run_dir
     }
+}
 
-    let res = child.wait()?;
-    if !res.success() {
-        return Err(anyhow!("cargo bench failed with code {:?}", res.code()));
+/// Where repetition `i` of a run stores its results, see [`REPEATS_DIR`]
+fn repetition_dir(run_dir: &Path, i: usize) -> PathBuf {
+    if i == 0 {
+        run_dir.to_path_buf()
+    } else {
+        run_dir.join(REPEATS_DIR).join(i.to_string())
     }
+}
+
+/// The repetitions stored in a run directory, the first one included
+fn repetitions(run_dir: &Path) -> Result<Vec<usize>> {
+    let mut repetitions = vec![0];
+    let repeats_dir = run_dir.join(REPEATS_DIR);
+    if repeats_dir.is_dir() {
+        for entry in std::fs::read_dir(&repeats_dir)? {
+            if let Some(i) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            {
+                repetitions.push(i);
+            }
+        }
+    }
+    Ok(repetitions)
+}
+
+fn bench_file(dir: &Path, id: &str) -> PathBuf {
+    dir.join(id).with_extension("json")
+}
 
-    Ok(bench_ids)
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Environment variables understood by `wgpu::util::initialize_adapter_from_env`
+fn adapter_env(system_info: &SystemInfo) -> Vec<(String, String)> {
+    let Some(info) = &system_info.wgpu_adapter_info else {
+        return Vec::new();
+    };
+    info!("forwarding adapter `{}` ({})", info.name, info.backend);
+    vec![
+        ("WGPU_ADAPTER_NAME".to_string(), info.name.clone()),
+        (
+            "WGPU_BACKEND".to_string(),
+            info.backend.to_str().to_string(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{system, temp_dir};
+    use serde_json::json;
+
+    const COMMIT: &str = "281ea13db54165e8362f2f5f85169561148eafba";
+
+    fn write(path: PathBuf, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn merging_keeps_the_results_of_dropped_benchmarks() {
+        let db_root = temp_dir("staging");
+        let run_dir = db_root.join(COMMIT).join("lab");
+        let mut manifest = system();
+        let fields = json!({
+            "format_version": CURRENT_FORMAT_VERSION,
+            "commit_hash": COMMIT,
+            "name": "lab",
+            "benchmarks": ["a", "b"],
+            "records": {
+                "a": { "timestamp": null, "system": 0, "repeats": 2 },
+                "b": { "timestamp": null, "system": 0, "repeats": 1 },
+            },
+            "systems": [system()],
+            "build": { "duration_secs": 60.0, "exit_code": 0, "warnings": 0, "compiled": 300, "fresh": 0 },
+        });
+        manifest
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        write(run_dir.join("run.json"), &manifest.to_string());
+        write(run_dir.join("a.json"), "old a");
+        write(run_dir.join("repeats/1/a.json"), "old a, repetition 1");
+        write(run_dir.join("b.json"), "old b");
+
+        let staging = RunStaging::prepare(&db_root, COMMIT, "lab", false, true).unwrap();
+        // A single repetition, that completed a but not b
+        write(staging.tmp_dir.join("a.json"), "new a");
+        write(staging.tmp_dir.join("b.json"), "partial b");
+        staging.discard(&["b"]).unwrap();
+        let completed = vec![CompletedBench {
+            id: "a".to_string(),
+            timestamp: "now".to_string(),
+        }];
+        let system_info = serde_json::from_value(system()).unwrap();
+        let build = BuildInfo {
+            duration_secs: 2.0,
+            exit_code: Some(0),
+            warnings: 0,
+            compiled: 1,
+            fresh: 299,
+        };
+        staging
+            .finish(
+                system_info,
+                completed,
+                1,
+                None,
+                Some((build, BuildMetrics::default())),
+            )
+            .unwrap();
+
+        assert_eq!(read(run_dir.join("a.json")), "new a");
+        assert!(!run_dir.join("repeats/1/a.json").exists());
+        assert_eq!(read(run_dir.join("b.json")), "old b");
+        let manifest = load_manifest(&run_dir).unwrap();
+        assert_eq!(manifest.records["a"].repeats, 1);
+        assert_eq!(manifest.records["b"].repeats, 1);
+        // The cold build of the full run, not the incremental one of the top-up
+        assert_eq!(manifest.build.unwrap().duration_secs, 60.0);
+
+        std::fs::remove_dir_all(&db_root).unwrap();
+    }
 }
@@ -1,60 +1,80 @@
-use crate::utils::run_git;
+use crate::commands::bench::{handle_interrupts, record_failure, BenchOptions};
+use crate::common::{BenchQueue, FailureStage, QueueEntry, QueueStatus};
+use crate::config::{SelectionConfig, Workspace};
+use crate::migrations::load_manifest;
+use crate::utils::{load_json, run_git, save_json};
 use anyhow::{Context, Result};
 use indicatif::ProgressStyle;
-use std::path::Path;
+use regex::Regex;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use tracing::{info, warn};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-/// Find PR-merged commits on origin/main that are missing benchmarks for the given machine name,
-/// then run benchmarks for each one.
-pub fn run(repo_dir: &Path, name: &str, force: bool, dry_run: bool) -> Result<()> {
-    let root_dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let db_root = root_dir.join("db");
+pub struct BenchMissingOptions {
+    pub bench: BenchOptions,
+    pub dry_run: bool,
+    pub resume: bool,
+    pub retry_failed: bool,
+    pub selection: SelectionConfig,
+    /// Benchmark each commit in a temporary worktree instead of checking it out in the repo
+    pub worktree: bool,
+}
 
-    // 1. Fetch latest
-    info!("Fetching origin...");
-    run_git(repo_dir, ["fetch", "origin"])?;
-
-    // 2. Get PR-merged commits (message ends with (#NNN))
-    let log_output = run_git(
-        repo_dir,
-        ["log", "origin/main", "--oneline", "--format=%H %s"],
-    )?;
-    let pr_commits: Vec<(String, String)> = log_output
-        .lines()
-        .filter_map(|line| {
-            let (hash, msg) = line.split_once(' ')?;
-            // Check if message ends with (#NNN)
-            if msg.ends_with(')')
-                && msg.rfind("(#").is_some_and(|i| {
-                    msg[i + 2..msg.len() - 1]
-                        .chars()
-                        .all(|c| c.is_ascii_digit())
-                })
+/// Find commits selected by the policy (PR-merged commits on the tracked branch by default) that are
+/// missing benchmarks for the given machine name, then run benchmarks for each one.
+///
+/// The planned queue is persisted to `db/bench-missing.<name>.json` and updated after every
+/// commit, so an interrupted invocation can be continued with `resume`.
+pub fn run(workspace: &Workspace, name: &str, options: &BenchMissingOptions) -> Result<()> {
+    let BenchMissingOptions {
+        dry_run,
+        resume,
+        retry_failed,
+        worktree,
+        ..
+    } = *options;
+
+    let repo_dir = workspace.repo_dir.as_path();
+    let state_path = workspace
+        .db_root
+        .join(format!("bench-missing.{}.json", name));
+
+    let mut queue = if resume {
+        let mut queue: BenchQueue = load_json(&state_path).with_context(|| {
+            format!(
+                "no bench-missing queue to resume at {}",
+                state_path.display()
+            )
+        })?;
+        // Entries left running were interrupted mid-benchmark
+        for entry in queue.entries.iter_mut() {
+            if entry.status == QueueStatus::Running
+                || (retry_failed && entry.status == QueueStatus::Failed)
             {
-                Some((hash.to_string(), msg.to_string()))
-            } else {
-                None
+                entry.status = QueueStatus::Pending;
             }
-        })
-        .collect();
+        }
+        info!("Resuming queue created at {}", queue.created_at);
+        queue
+    } else {
+        if state_path.exists() {
+            warn!(
+                "Discarding previous queue at {}, use --resume to continue it",
+                state_path.display()
+            );
+        }
+        plan_queue(workspace, name, options)?
+    };
 
-    // 3. Find which commits are missing benchmarks for this machine
-    let missing: Vec<&(String, String)> = pr_commits
-        .iter()
-        .filter(|(hash, _)| {
-            let run_dir = db_root.join(hash).join(name);
-            if force {
-                true
-            } else {
-                !run_dir.exists()
-            }
-        })
+    let pending: Vec<usize> = (0..queue.entries.len())
+        .filter(|&i| queue.entries[i].status == QueueStatus::Pending)
         .collect();
 
-    if missing.is_empty() {
+    if pending.is_empty() {
         info!(
-            "All PR commits already have '{}' benchmarks. Nothing to do.",
+            "All selected commits already have '{}' benchmarks. Nothing to do.",
             name
         );
         return Ok(());
@@ -63,11 +83,12 @@ pub fn run(repo_dir: &Path, name: &str, force: bool, dry_run: bool) -> Result<()
     // Show what we'll run (oldest first)
     info!(
         "Found {} commits missing '{}' benchmarks:",
-        missing.len(),
+        pending.len(),
         name
     );
-    for (hash, msg) in missing.iter().rev() {
-        info!("  {} {}", &hash[..8], msg);
+    for &i in &pending {
+        let entry = &queue.entries[i];
+        info!("  {} {}", &entry.commit_hash[..8], entry.message);
     }
 
     if dry_run {
@@ -75,9 +96,34 @@ pub fn run(repo_dir: &Path, name: &str, force: bool, dry_run: bool) -> Result<()
         return Ok(());
     }
 
+    save_json(&state_path, &queue, true)?;
+
+    // An interrupt terminates the benchmark process, then we stop scheduling new commits and
+    // let the guards below restore the submodule or remove the worktree
+    let interrupted = handle_interrupts()?;
+    let mut bench_options = options.bench.clone();
+    bench_options.interrupted = Some(interrupted.clone());
+    let _head_guard = if worktree {
+        // Worktrees share a target directory so each commit only rebuilds what changed
+        if !bench_options
+            .env
+            .iter()
+            .any(|(k, _)| k == "CARGO_TARGET_DIR")
+        {
+            let target_dir = repo_dir.join("target").join("bench-worktrees");
+            info!("Building worktrees in {}", target_dir.display());
+            bench_options.env.push((
+                "CARGO_TARGET_DIR".to_string(),
+                target_dir.display().to_string(),
+            ));
+        }
+        None
+    } else {
+        Some(HeadGuard::new(repo_dir)?)
+    };
+
     // 4. Run benchmarks oldest-first
-    let to_run: Vec<_> = missing.into_iter().rev().cloned().collect();
-    let total = to_run.len();
+    let total = pending.len();
 
     let batch_span = tracing::info_span!("bench-missing");
     batch_span.pb_set_style(
@@ -90,31 +136,369 @@ pub fn run(repo_dir: &Path, name: &str, force: bool, dry_run: bool) -> Result<()
     batch_span.pb_set_length(total as u64);
     let _batch_guard = batch_span.enter();
 
-    for (hash, msg) in to_run.iter() {
-        batch_span.pb_set_message(&format!("{} {}", &hash[..8], msg));
+    let mut completed = 0;
+    for i in pending {
+        if interrupted.load(Ordering::SeqCst) {
+            break;
+        }
 
-        // Checkout
-        run_git(repo_dir, ["checkout", hash])
-            .with_context(|| format!("Failed to checkout {}", hash))?;
+        let hash = queue.entries[i].commit_hash.clone();
+        batch_span.pb_set_message(&format!("{} {}", &hash[..8], queue.entries[i].message));
+        queue.entries[i].status = QueueStatus::Running;
+        save_json(&state_path, &queue, true)?;
 
-        // Run benchmark
-        match crate::commands::bench::run(repo_dir, name, force) {
-            Ok(()) => info!("Completed benchmark for {}", &hash[..8]),
+        let checkout = if worktree {
+            Worktree::add(repo_dir, name, &hash).map(Some)
+        } else {
+            run_git(repo_dir, ["checkout", &hash]).map(|_| None)
+        };
+        let result = match checkout {
+            Ok(worktree) => {
+                let checkout_workspace = worktree.as_ref().map(|w| workspace.at_checkout(&w.path));
+                crate::commands::bench::run(
+                    checkout_workspace.as_ref().unwrap_or(workspace),
+                    name,
+                    &bench_options,
+                )
+            }
+            Err(e) if interrupted.load(Ordering::SeqCst) => Err(e),
+            Err(e) => {
+                let e = e.context(format!("Failed to checkout {}", hash));
+                let run_dir = workspace.db_root.join(&hash).join(name);
+                record_failure(&run_dir, &hash, name, FailureStage::Checkout, &e)?;
+                Err(e)
+            }
+        };
+
+        let entry = &mut queue.entries[i];
+        match result {
+            Ok(()) => {
+                info!("Completed benchmark for {}", &hash[..8]);
+                entry.status = QueueStatus::Done;
+                entry.error = None;
+                completed += 1;
+            }
+            Err(_) if interrupted.load(Ordering::SeqCst) => {
+                entry.status = QueueStatus::Pending;
+            }
             Err(e) => {
                 warn!("Benchmark failed for {}: {}", &hash[..8], e);
                 warn!("Continuing with next commit...");
+                entry.status = QueueStatus::Failed;
+                entry.error = Some(format!("{e:#}"));
             }
         }
+        save_json(&state_path, &queue, true)?;
 
         batch_span.pb_inc(1);
     }
 
-    // 5. Restore to latest benchmarked commit
-    if let Some((latest_hash, _)) = to_run.last() {
-        info!("Restoring submodule to {}", &latest_hash[..8]);
-        run_git(repo_dir, ["checkout", latest_hash])?;
+    if interrupted.load(Ordering::SeqCst) {
+        warn!("Interrupted! Run again with --resume to continue the queue.");
+    } else if queue.entries.iter().all(|e| e.status == QueueStatus::Done) {
+        std::fs::remove_file(&state_path)?;
+    } else {
+        warn!(
+            "Some commits failed, see {} for details.",
+            state_path.display()
+        );
     }
 
-    info!("Done! Benchmarked {} commits.", total);
+    info!("Done! Benchmarked {} commits.", completed);
     Ok(())
 }
+
+/// Plan the queue of selected commits that are missing benchmarks for the given machine name
+fn plan_queue(
+    workspace: &Workspace,
+    name: &str,
+    options: &BenchMissingOptions,
+) -> Result<BenchQueue> {
+    let BenchMissingOptions {
+        bench: BenchOptions { force, filter, .. },
+        retry_failed,
+        selection,
+        ..
+    } = options;
+    let filter_regex = filter
+        .criterion_filter()
+        .map(|f| Regex::new(&f))
+        .transpose()
+        .context("invalid benchmark filter")?;
+
+    // 1. Fetch latest
+    info!("Fetching {}...", workspace.remote);
+    run_git(&workspace.repo_dir, ["fetch", &workspace.remote])?;
+
+    // 2. Get the commits selected by the policy
+    let selected = select_commits(&workspace.repo_dir, selection, &workspace.branch)?;
+
+    // 3. Find which commits are missing benchmarks for this machine
+    let mut known_failures = 0;
+    let missing: Vec<&(String, String)> = selected
+        .iter()
+        .filter(|(hash, _)| {
+            let run_dir = workspace.db_root.join(hash).join(name);
+            if *force {
+                true
+            } else if run_dir.join("run.json").exists() {
+                // With a filter, a run is only complete if it has some of the filtered benchmarks
+                filter_regex.as_ref().is_some_and(|re| {
+                    load_manifest(&run_dir)
+                        .is_ok_and(|m| !m.benchmarks.iter().any(|id| re.is_match(id)))
+                })
+            } else if run_dir.join("failure.json").exists() && !*retry_failed {
+                known_failures += 1;
+                false
+            } else {
+                true
+            }
+        })
+        .collect();
+    if known_failures > 0 {
+        info!(
+            "Skipping {} commits that failed before, use --retry-failed to retry them",
+            known_failures
+        );
+    }
+
+    // Oldest first
+    Ok(BenchQueue {
+        name: name.to_string(),
+        created_at: chrono::Local::now().to_rfc3339(),
+        entries: missing
+            .into_iter()
+            .rev()
+            .map(|(hash, msg)| QueueEntry {
+                commit_hash: hash.clone(),
+                message: msg.clone(),
+                status: QueueStatus::Pending,
+                error: None,
+            })
+            .collect(),
+    })
+}
+
+/// Checks the original HEAD of the repo back out when dropped
+struct HeadGuard<'a> {
+    repo_dir: &'a Path,
+    original: String,
+}
+
+impl<'a> HeadGuard<'a> {
+    fn new(repo_dir: &'a Path) -> Result<Self> {
+        // Prefer the branch name so we don't leave the repo detached
+        let original = match run_git(repo_dir, ["symbolic-ref", "-q", "--short", "HEAD"]) {
+            Ok(branch) => branch,
+            Err(_) => run_git(repo_dir, ["rev-parse", "HEAD"])?,
+        };
+        Ok(Self {
+            repo_dir,
+            original: original.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for HeadGuard<'_> {
+    fn drop(&mut self) {
+        info!("Restoring submodule to {}", self.original);
+        if let Err(e) = run_git(self.repo_dir, ["checkout", &self.original]) {
+            warn!("Failed to restore submodule to {}: {}", self.original, e);
+        }
+    }
+}
+
+/// A detached worktree of the repo at some commit, removed when dropped
+struct Worktree<'a> {
+    repo_dir: &'a Path,
+    path: PathBuf,
+}
+
+impl<'a> Worktree<'a> {
+    /// Check out `hash` in a directory of its own, named after the run and the process so
+    /// concurrent invocations, even of the same machine, never share one
+    fn add(repo_dir: &'a Path, name: &str, hash: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "ranim-bench-{}-{}-{}",
+            name,
+            std::process::id(),
+            &hash[..12]
+        ));
+        // Left over by a worktree of this process that couldn't be removed
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        run_git(repo_dir, ["worktree", "prune"])?;
+        run_git(
+            repo_dir,
+            ["worktree", "add", "--detach", &path.to_string_lossy(), hash],
+        )?;
+        let worktree = Self { repo_dir, path };
+        if worktree.path.join(".gitmodules").exists() {
+            run_git(
+                &worktree.path,
+                ["submodule", "update", "--init", "--recursive"],
+            )?;
+        }
+        info!("Checked out {} in {}", &hash[..8], worktree.path.display());
+        Ok(worktree)
+    }
+}
+
+impl Drop for Worktree<'_> {
+    fn drop(&mut self) {
+        let path = self.path.to_string_lossy();
+        if let Err(e) = run_git(self.repo_dir, ["worktree", "remove", "--force", &path]) {
+            warn!("Failed to remove worktree {}: {}", path, e);
+        }
+    }
+}
+
+/// Subjects of PR squash merges, e.g. "Fix foo (#123)"
+const DEFAULT_SUBJECT_REGEX: &str = r"\(#\d+\)$";
+
+/// List the commits selected by `selection`, newest first. Without a branch or range in
+/// `selection`, the history of `default_branch` is searched.
+fn select_commits(
+    repo_dir: &Path,
+    selection: &SelectionConfig,
+    default_branch: &str,
+) -> Result<Vec<(String, String)>> {
+    let rev = selection
+        .range
+        .as_deref()
+        .or(selection.branch.as_deref())
+        .unwrap_or(default_branch);
+    let mut args = vec![
+        "log".to_string(),
+        rev.to_string(),
+        "--format=%H %s".to_string(),
+    ];
+    if selection.first_parent == Some(true) {
+        args.push("--first-parent".to_string());
+    }
+    if let Some(since) = &selection.since {
+        args.push(format!("--since={}", since));
+    }
+    let log_output = run_git(repo_dir, &args)?;
+
+    let tags = selection.tags.unwrap_or(false);
+    let tagged: HashSet<String> = if tags {
+        run_git(repo_dir, ["rev-list", "--no-walk", "--tags"])?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    // With --tags alone only tagged commits are selected
+    let subject_regex = match (&selection.subject_regex, tags) {
+        (Some(re), _) => Some(re.as_str()),
+        (None, false) => Some(DEFAULT_SUBJECT_REGEX),
+        (None, true) => None,
+    }
+    .map(Regex::new)
+    .transpose()
+    .context("invalid subject regex")?;
+
+    let every = selection.every.unwrap_or(1).max(1);
+    let mut selected: Vec<(String, String)> = log_output
+        .lines()
+        .filter_map(|line| {
+            let (hash, msg) = line.split_once(' ').unwrap_or((line, ""));
+            let selected =
+                subject_regex.as_ref().is_some_and(|re| re.is_match(msg)) || tagged.contains(hash);
+            selected.then(|| (hash.to_string(), msg.to_string()))
+        })
+        .collect();
+    // The log is newest first, count from the oldest commit so new ones don't shift the picks
+    selected.reverse();
+    let mut picked: Vec<_> = selected.into_iter().step_by(every).collect();
+    picked.reverse();
+    Ok(picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    /// A repository with the given commit subjects, oldest first
+    fn test_repo(name: &str, subjects: &[&str]) -> PathBuf {
+        let dir = temp_dir(name);
+        run_git(&dir, ["init", "-q", "-b", "main"]).unwrap();
+        for subject in subjects {
+            commit(&dir, subject);
+        }
+        dir
+    }
+
+    fn commit(dir: &Path, subject: &str) {
+        run_git(
+            dir,
+            [
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "-q",
+                "--allow-empty",
+                "-m",
+                subject,
+            ],
+        )
+        .unwrap();
+    }
+
+    fn subjects(selected: &[(String, String)]) -> Vec<&str> {
+        selected.iter().map(|(_, msg)| msg.as_str()).collect()
+    }
+
+    #[test]
+    fn selects_merged_prs_by_default() {
+        let dir = test_repo("default", &["a (#1)", "direct push", "b (#2)"]);
+        let selected = select_commits(&dir, &SelectionConfig::default(), "main").unwrap();
+        assert_eq!(subjects(&selected), ["b (#2)", "a (#1)"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_counts_from_the_oldest_commit() {
+        let dir = test_repo("every", &["a (#1)", "b (#2)", "c (#3)", "d (#4)"]);
+        let selection = SelectionConfig {
+            every: Some(2),
+            ..Default::default()
+        };
+        let selected = select_commits(&dir, &selection, "main").unwrap();
+        assert_eq!(subjects(&selected), ["c (#3)", "a (#1)"]);
+
+        // A new commit doesn't change which of the older ones are picked
+        commit(&dir, "e (#5)");
+        let selected = select_commits(&dir, &selection, "main").unwrap();
+        assert_eq!(subjects(&selected), ["e (#5)", "c (#3)", "a (#1)"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn subject_regex_and_tags() {
+        let dir = test_repo("tags", &["release: 0.1", "a (#1)", "chore"]);
+        run_git(&dir, ["tag", "v0.1", "HEAD~2"]).unwrap();
+
+        let tags_only = SelectionConfig {
+            tags: Some(true),
+            ..Default::default()
+        };
+        let selected = select_commits(&dir, &tags_only, "main").unwrap();
+        assert_eq!(subjects(&selected), ["release: 0.1"]);
+
+        let regex_and_tags = SelectionConfig {
+            tags: Some(true),
+            subject_regex: Some("^chore$".to_string()),
+            ..Default::default()
+        };
+        let selected = select_commits(&dir, &regex_and_tags, "main").unwrap();
+        assert_eq!(subjects(&selected), ["chore", "release: 0.1"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use crate::commands::graph::load_bench_value;
+use crate::common::{BenchValue, OutputFormat};
+use crate::config::Workspace;
+use crate::migrations::load_manifest;
+use crate::utils::resolve_commit;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use tracing::warn;
+
+#[derive(Debug, Serialize)]
+struct CompareReport {
+    base_commit: String,
+    head_commit: String,
+    machine: String,
+    benchmarks: Vec<BenchComparison>,
+    /// Benchmarks only present in the head run
+    added: Vec<String>,
+    /// Benchmarks only present in the base run
+    removed: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BenchComparison {
+    bench_id: String,
+    base: Option<BenchValue>,
+    head: Option<BenchValue>,
+    /// head / base of the mean estimates, none for a zero baseline
+    ratio: Option<f64>,
+    /// Whether the confidence intervals of the two means overlap, none if either is missing
+    /// or has no interval
+    overlap: Option<bool>,
+}
+
+/// Compare the runs of `name` on two commits of the repo.
+pub fn run(
+    workspace: &Workspace,
+    base: &str,
+    head: &str,
+    name: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    let repo_dir = &workspace.repo_dir;
+    let db_root = &workspace.db_root;
+
+    let base_commit = resolve_commit(repo_dir, base)?;
+    let head_commit = resolve_commit(repo_dir, head)?;
+
+    let base_dir = db_root.join(&base_commit).join(name);
+    let head_dir = db_root.join(&head_commit).join(name);
+    let base_manifest = load_manifest(&base_dir)
+        .with_context(|| format!("no '{}' run found for {}", name, base_commit))?;
+    let head_manifest = load_manifest(&head_dir)
+        .with_context(|| format!("no '{}' run found for {}", name, head_commit))?;
+
+    let base_ids: BTreeSet<&String> = base_manifest.benchmarks.iter().collect();
+    let head_ids: BTreeSet<&String> = head_manifest.benchmarks.iter().collect();
+
+    let benchmarks = base_ids
+        .intersection(&head_ids)
+        .map(|&bench_id| {
+            let load = |run_dir: &Path| {
+                let path = run_dir.join(bench_id).with_extension("json");
+                load_bench_value(&path)
+                    .inspect_err(|e| warn!("Failed to parse {}: {}", path.display(), e))
+                    .ok()
+            };
+            let base = load(&base_dir);
+            let head = load(&head_dir);
+            let (ratio, overlap) = match (&base, &head) {
+                (Some(base), Some(head)) if base.unit == head.unit => {
+                    let ratio = (base.estimate != 0.0).then(|| head.estimate / base.estimate);
+                    (ratio, head.overlaps(base))
+                }
+                _ => (None, None),
+            };
+            BenchComparison {
+                bench_id: bench_id.clone(),
+                base,
+                head,
+                ratio,
+                overlap,
+            }
+        })
+        .collect();
+
+    let report = CompareReport {
+        base_commit,
+        head_commit,
+        machine: name.to_string(),
+        benchmarks,
+        added: head_ids
+            .difference(&base_ids)
+            .map(|s| s.to_string())
+            .collect(),
+        removed: base_ids
+            .difference(&head_ids)
+            .map(|s| s.to_string())
+            .collect(),
+    };
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        OutputFormat::Text => print_text(&report),
+    }
+    Ok(())
+}
+
+fn print_text(report: &CompareReport) {
+    println!(
+        "{} ({}) -> {} ({})",
+        &report.base_commit[..8],
+        report.machine,
+        &report.head_commit[..8],
+        report.machine
+    );
+    println!();
+    println!(
+        "{:<40} {:>16} {:>16} {:>8} {:>8}",
+        "benchmark", "base mean", "head mean", "ratio", "overlap"
+    );
+    for bench in &report.benchmarks {
+        let fmt_value = |value: &Option<BenchValue>| match value {
+            Some(v) => format!("{:.1} {}", v.estimate, v.unit),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<40} {:>16} {:>16} {:>8} {:>8}",
+            bench.bench_id,
+            fmt_value(&bench.base),
+            fmt_value(&bench.head),
+            bench.ratio.map_or("-".to_string(), |r| format!("{:.3}", r)),
+            match bench.overlap {
+                Some(true) => "yes",
+                Some(false) => "NO",
+                // Both values are there, but without an interval to compare
+                None if bench.base.is_some() && bench.head.is_some() => "?",
+                None => "-",
+            }
+        );
+    }
+
+    if !report.added.is_empty() {
+        println!();
+        println!("Added:");
+        for id in &report.added {
+            println!("  + {}", id);
+        }
+    }
+    if !report.removed.is_empty() {
+        println!();
+        println!("Removed:");
+        for id in &report.removed {
+            println!("  - {}", id);
+        }
+    }
+}
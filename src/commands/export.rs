@@ -0,0 +1,277 @@
+use crate::commands::bench::BenchFilter;
+use crate::commands::graph::scan_db;
+use crate::common::{BenchValue, Estimate};
+use crate::config::Workspace;
+use crate::utils::run_git;
+use anyhow::{anyhow, Context, Result};
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+/// A statistic of a benchmark value, as named in exports and `query --stat`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Statistic {
+    #[default]
+    Mean,
+    Median,
+    Slope,
+    MedianAbsDev,
+    Typical,
+    /// Elements/bytes per second
+    Throughput,
+    /// Mean across `bench --repeat` runs, bounded by the extreme runs
+    RepeatMean,
+}
+
+impl Statistic {
+    pub fn name(self) -> &'static str {
+        match self {
+            Statistic::Mean => "mean",
+            Statistic::Median => "median",
+            Statistic::Slope => "slope",
+            Statistic::MedianAbsDev => "median_abs_dev",
+            Statistic::Typical => "typical",
+            Statistic::Throughput => "throughput",
+            Statistic::RepeatMean => "repeat_mean",
+        }
+    }
+}
+
+pub struct ExportOptions {
+    pub output: PathBuf,
+    /// Guessed from the extension of `output` if not given
+    pub format: Option<ExportFormat>,
+    /// Only export these machines, all if empty
+    pub machines: Vec<String>,
+    pub filter: BenchFilter,
+    /// Only export the commits of this revision range
+    pub range: Option<String>,
+}
+
+/// One statistic of one benchmark
+#[derive(Debug, Serialize)]
+struct Row {
+    commit: String,
+    commit_date: Option<String>,
+    machine: String,
+    bench_id: String,
+    statistic: &'static str,
+    estimate: f64,
+    lower_bound: Option<f64>,
+    upper_bound: Option<f64>,
+    unit: String,
+}
+
+/// Export the scanned db as a flat table, one row per statistic.
+pub fn run(workspace: &Workspace, options: &ExportOptions) -> Result<()> {
+    let repo_dir = &workspace.repo_dir;
+    let format = match options.format {
+        Some(format) => format,
+        None => match options.output.extension().and_then(|e| e.to_str()) {
+            Some("csv") => ExportFormat::Csv,
+            Some("parquet") => ExportFormat::Parquet,
+            _ => {
+                return Err(anyhow!(
+                    "can't tell the format of {}, use --format",
+                    options.output.display()
+                ));
+            }
+        },
+    };
+    let filter = options
+        .filter
+        .criterion_filter()
+        .map(|f| Regex::new(&f))
+        .transpose()
+        .context("invalid benchmark filter")?;
+
+    info!("Scanning db/ for benchmark data...");
+    let all_data = scan_db(&workspace.db_root)?;
+
+    // Commit dates, limited to the range if one is given
+    let log = match &options.range {
+        Some(range) => run_git(repo_dir, ["log", "--format=%H %cI", range.as_str()])?,
+        None => run_git(repo_dir, ["log", "--all", "--format=%H %cI"])?,
+    };
+    let dates: HashMap<&str, &str> = log
+        .lines()
+        .filter_map(|line| line.trim().split_once(' '))
+        .collect();
+
+    let mut rows = Vec::new();
+    for (commit, data) in &all_data.commits {
+        let commit_date = dates.get(commit.as_str());
+        if options.range.is_some() && commit_date.is_none() {
+            continue;
+        }
+        for (machine, results) in &data.benchmarks {
+            if !options.machines.is_empty() && !options.machines.contains(machine) {
+                continue;
+            }
+            for (bench_id, value) in results {
+                if filter.as_ref().is_some_and(|f| !f.is_match(bench_id)) {
+                    continue;
+                }
+                for stat in statistics(value) {
+                    rows.push(Row {
+                        commit: commit.clone(),
+                        commit_date: commit_date.map(|d| d.to_string()),
+                        machine: machine.clone(),
+                        bench_id: bench_id.clone(),
+                        statistic: stat.statistic.name(),
+                        estimate: stat.estimate,
+                        lower_bound: stat.lower_bound,
+                        upper_bound: stat.upper_bound,
+                        unit: stat.unit,
+                    });
+                }
+            }
+        }
+    }
+    rows.sort_by(|a, b| {
+        (&a.commit_date, &a.commit, &a.machine, &a.bench_id).cmp(&(
+            &b.commit_date,
+            &b.commit,
+            &b.machine,
+            &b.bench_id,
+        ))
+    });
+
+    match format {
+        ExportFormat::Csv => write_csv(&options.output, &rows)?,
+        ExportFormat::Parquet => write_parquet(&options.output, &rows)?,
+    }
+    info!(
+        "Exported {} rows to {}",
+        rows.len(),
+        options.output.display()
+    );
+    Ok(())
+}
+
+/// A statistic of a benchmark value
+pub struct Stat {
+    pub statistic: Statistic,
+    pub estimate: f64,
+    pub lower_bound: Option<f64>,
+    pub upper_bound: Option<f64>,
+    pub unit: String,
+}
+
+impl Stat {
+    fn from_estimate(statistic: Statistic, estimate: &Estimate) -> Self {
+        Stat {
+            statistic,
+            estimate: estimate.estimate,
+            lower_bound: Some(estimate.lower_bound),
+            upper_bound: Some(estimate.upper_bound),
+            unit: estimate.unit.clone(),
+        }
+    }
+}
+
+/// The statistics available for a benchmark value
+pub fn statistics(value: &BenchValue) -> Vec<Stat> {
+    let mut stats = vec![Stat {
+        statistic: Statistic::Mean,
+        estimate: value.estimate,
+        lower_bound: Some(value.lower_bound),
+        upper_bound: Some(value.upper_bound),
+        unit: value.unit.clone(),
+    }];
+    let estimates = [
+        (Statistic::Median, &value.median),
+        (Statistic::Slope, &value.slope),
+        (Statistic::MedianAbsDev, &value.median_abs_dev),
+        (Statistic::Typical, &value.typical),
+    ];
+    for (statistic, estimate) in estimates {
+        if let Some(estimate) = estimate {
+            stats.push(Stat::from_estimate(statistic, estimate));
+        }
+    }
+    if let Some(throughput) = &value.throughput {
+        stats.push(Stat {
+            statistic: Statistic::Throughput,
+            estimate: throughput.per_second,
+            lower_bound: None,
+            upper_bound: None,
+            unit: format!("{}/s", throughput.unit),
+        });
+    }
+    // Spread of the mean across `bench --repeat` runs
+    if let Some(repeats) = &value.repeats {
+        stats.push(Stat {
+            statistic: Statistic::RepeatMean,
+            estimate: repeats.estimate,
+            lower_bound: Some(repeats.min),
+            upper_bound: Some(repeats.max),
+            unit: value.unit.clone(),
+        });
+    }
+    stats
+}
+
+fn write_csv(path: &Path, rows: &[Row]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_parquet(path: &Path, rows: &[Row]) -> Result<()> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("commit", DataType::Utf8, false),
+        Field::new("commit_date", DataType::Utf8, true),
+        Field::new("machine", DataType::Utf8, false),
+        Field::new("bench_id", DataType::Utf8, false),
+        Field::new("statistic", DataType::Utf8, false),
+        Field::new("estimate", DataType::Float64, false),
+        Field::new("lower_bound", DataType::Float64, true),
+        Field::new("upper_bound", DataType::Float64, true),
+        Field::new("unit", DataType::Utf8, false),
+    ]));
+    let strings = |f: fn(&Row) -> &str| -> ArrayRef {
+        Arc::new(rows.iter().map(f).map(Some).collect::<StringArray>())
+    };
+    let columns: Vec<ArrayRef> = vec![
+        strings(|r| &r.commit),
+        Arc::new(
+            rows.iter()
+                .map(|r| r.commit_date.as_deref())
+                .collect::<StringArray>(),
+        ),
+        strings(|r| &r.machine),
+        strings(|r| &r.bench_id),
+        strings(|r| r.statistic),
+        Arc::new(Float64Array::from_iter_values(
+            rows.iter().map(|r| r.estimate),
+        )),
+        Arc::new(rows.iter().map(|r| r.lower_bound).collect::<Float64Array>()),
+        Arc::new(rows.iter().map(|r| r.upper_bound).collect::<Float64Array>()),
+        strings(|r| &r.unit),
+    ];
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    let mut writer = ArrowWriter::try_new(file, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
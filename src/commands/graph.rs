@@ -1,4 +1,11 @@
-use crate::common::{AllData, BenchValue, CommitBenchData, CommitRecord, RunManifest};
+use crate::columnar;
+use crate::common::{
+    AllData, BenchResult, BenchValue, BuildMetrics, CommitBenchData, CommitRecord, FailureRecord,
+    RepeatStats, RunManifest, BUILD_GROUP, CANONICAL_TIME_UNIT, METRICS_FILE, REPEATS_DIR,
+};
+use crate::config::Workspace;
+use crate::index::Index;
+use crate::migrations::load_manifest;
 use crate::utils::{load_json, run_git, save_json};
 use anyhow::{anyhow, Result};
 use git2::Repository;
@@ -7,17 +14,18 @@ use git_graph::print::format::CommitFormat;
 use git_graph::settings::{
     BranchOrder, BranchSettings, BranchSettingsDef, Characters, MergePatterns, Settings,
 };
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use tracing::{info, warn};
 
-pub fn run(root_dir: &Path, repo_dir: &Path) -> Result<()> {
-    let db_root = root_dir.join("db");
-    let web_public_dir = root_dir.join("web").join("public");
+pub fn run(workspace: &Workspace) -> Result<()> {
+    let db_root = &workspace.db_root;
+    let repo_dir = &workspace.repo_dir;
+    let web_public_dir = &workspace.output_dir;
 
     // 1. Scan db/ and build aggregated data
     info!("Scanning db/ for benchmark data...");
-    let all_data = scan_db(&db_root)?;
+    let all_data = scan_db(db_root)?;
     info!(
         "Found {} commits, {} machines",
         all_data.commits.len(),
@@ -26,7 +34,7 @@ pub fn run(root_dir: &Path, repo_dir: &Path) -> Result<()> {
 
     // 2. Generate git-graph
     info!("Fetching repo...");
-    run_git(repo_dir, &["fetch", "--all"])?;
+    run_git(repo_dir, ["fetch", "--all"])?;
 
     info!("Opening repository at {}...", repo_dir.display());
     let repo = Repository::open(repo_dir)?;
@@ -58,7 +66,7 @@ pub fn run(root_dir: &Path, repo_dir: &Path) -> Result<()> {
     );
     let mut records = Vec::new();
 
-    for (_i, commit_info) in graph.commits.iter().enumerate() {
+    for commit_info in graph.commits.iter() {
         let commit = graph.commit(commit_info.oid)?;
         let parents: Vec<String> = commit.parents().map(|p| p.id().to_string()).collect();
         let author = commit.author();
@@ -109,21 +117,60 @@ pub fn run(root_dir: &Path, repo_dir: &Path) -> Result<()> {
     }
 
     // 3. Save outputs
-    save_json(web_public_dir.join("git-graph.json"), &records)?;
+    save_json(web_public_dir.join("git-graph.json"), &records, true)?;
     info!("Saved {} commits to git-graph.json", records.len());
 
-    save_json(web_public_dir.join("all-data.json"), &all_data)?;
+    save_json(web_public_dir.join("all-data.json"), &all_data, true)?;
     info!("Saved all-data.json");
 
+    let manifest = columnar::write(&all_data, &web_public_dir.join("data"))?;
+    info!(
+        "Saved columnar data with {} shards to data/",
+        manifest.shards.len()
+    );
+
     Ok(())
 }
 
-/// Scan the db/ directory and build aggregated AllData
-fn scan_db(db_root: &Path) -> Result<AllData> {
+/// Scan the db/ directory and build aggregated AllData, through the SQLite index if one was
+/// created with `ranim-bench index`
+pub fn scan_db(db_root: &Path) -> Result<AllData> {
+    match Index::open_existing(db_root) {
+        Ok(Some(mut index)) => match index.refresh(db_root).and_then(|_| index.load_all_data()) {
+            Ok(all_data) => return Ok(all_data),
+            Err(e) => warn!("Failed to read the index, scanning db/ instead: {:#}", e),
+        },
+        Ok(None) => {}
+        Err(e) => warn!("Failed to open the index, scanning db/ instead: {:#}", e),
+    }
+
     let mut all_data = AllData::default();
+    for CommitDir { commit_hash, runs } in run_dirs(db_root)? {
+        let mut commit_data = CommitBenchData::default();
+        for (machine_name, run_path) in runs {
+            let run = scan_run(&run_path, &commit_hash, &machine_name);
+            add_run(&mut all_data, &mut commit_data, machine_name, run);
+        }
+        if !commit_data.machines.is_empty() || !commit_data.failures.is_empty() {
+            all_data.commits.insert(commit_hash, commit_data);
+        }
+    }
+
+    Ok(all_data)
+}
+
+/// A commit directory of db/
+pub struct CommitDir {
+    pub commit_hash: String,
+    /// (machine name, run directory)
+    pub runs: Vec<(String, PathBuf)>,
+}
 
+/// The run directories of db/, grouped by commit
+pub fn run_dirs(db_root: &Path) -> Result<Vec<CommitDir>> {
+    let mut commits = Vec::new();
     if !db_root.exists() {
-        return Ok(all_data);
+        return Ok(commits);
     }
 
     for entry in std::fs::read_dir(db_root)? {
@@ -139,74 +186,200 @@ fn scan_db(db_root: &Path) -> Result<AllData> {
             continue;
         }
 
-        let mut commit_data = CommitBenchData::default();
-
+        let mut runs = Vec::new();
         for run_entry in std::fs::read_dir(&path)? {
             let run_entry = run_entry?;
             let run_path = run_entry.path();
-            if !run_path.is_dir() {
-                continue;
+            let name = run_entry.file_name().into_string().unwrap();
+            // Skip the staging directories of runs being written, or left over by a crash
+            if run_path.is_dir() && !name.ends_with(".tmp") {
+                runs.push((name, run_path));
             }
+        }
+        commits.push(CommitDir { commit_hash, runs });
+    }
+    Ok(commits)
+}
 
-            let machine_name = run_entry.file_name().into_string().unwrap();
+/// What `scan_db` keeps of a run directory
+#[derive(Default)]
+pub struct RunData {
+    /// The last failed attempt, if any
+    pub failure: Option<FailureRecord>,
+    /// None if the run has no readable run.json
+    pub manifest: Option<RunManifest>,
+    pub benchmarks: HashMap<String, BenchValue>,
+}
 
-            // Load run.json for system info
-            let run_json_path = run_path.join("run.json");
-            if !run_json_path.exists() {
-                warn!("Missing run.json for {}/{}", commit_hash, machine_name);
-                continue;
-            }
+/// Load a run directory, warning about the files that can't be read
+pub fn scan_run(run_path: &Path, commit_hash: &str, machine_name: &str) -> RunData {
+    let mut run = RunData::default();
 
-            let run_manifest: RunManifest = match load_json(&run_json_path) {
-                Ok(m) => m,
-                Err(e) => {
-                    warn!(
-                        "Failed to parse run.json for {}/{}: {}",
-                        commit_hash, machine_name, e
-                    );
-                    continue;
-                }
-            };
-
-            // Update machine system info (keep the latest one seen)
-            all_data
-                .machines
-                .insert(machine_name.clone(), run_manifest.system);
-
-            // Load each benchmark result
-            let mut bench_results = HashMap::new();
-            for bench_id in &run_manifest.benchmarks {
-                let bench_path = run_path.join(bench_id).with_extension("json");
-                if let Ok(json) = std::fs::read_to_string(&bench_path) {
-                    if let Ok(val) = serde_json::from_str::<serde_json::Value>(&json) {
-                        if let (Some(estimate), Some(unit)) = (
-                            val.get("mean")
-                                .and_then(|m| m.get("estimate"))
-                                .and_then(|e| e.as_f64()),
-                            val.get("mean")
-                                .and_then(|m| m.get("unit"))
-                                .and_then(|u| u.as_str()),
-                        ) {
-                            bench_results.insert(
-                                bench_id.clone(),
-                                BenchValue {
-                                    estimate,
-                                    unit: unit.to_string(),
-                                },
-                            );
-                        }
-                    }
-                }
-            }
+    // Record the last failed attempt, if any
+    let failure_path = run_path.join("failure.json");
+    let has_failure = failure_path.exists();
+    if has_failure {
+        match load_json::<FailureRecord>(&failure_path) {
+            Ok(failure) => run.failure = Some(failure),
+            Err(e) => warn!(
+                "Failed to parse failure.json for {}/{}: {}",
+                commit_hash, machine_name, e
+            ),
+        }
+    }
 
-            commit_data.machines.push(machine_name.clone());
-            commit_data.benchmarks.insert(machine_name, bench_results);
+    // Load run.json for system info
+    let run_json_path = run_path.join("run.json");
+    if !run_json_path.exists() {
+        if !has_failure {
+            warn!("Missing run.json for {}/{}", commit_hash, machine_name);
         }
+        return run;
+    }
 
-        if !commit_data.machines.is_empty() {
-            all_data.commits.insert(commit_hash, commit_data);
+    let run_manifest = match load_manifest(run_path) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!(
+                "Failed to parse run.json for {}/{}: {}",
+                commit_hash, machine_name, e
+            );
+            return run;
+        }
+    };
+
+    // Load each benchmark result
+    for bench_id in &run_manifest.benchmarks {
+        let bench_path = run_path.join(bench_id).with_extension("json");
+        let mut value = match load_bench_value(&bench_path) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Failed to parse {}: {}", bench_path.display(), e);
+                continue;
+            }
+        };
+        let repeats = run_manifest.records.get(bench_id).map_or(1, |r| r.repeats);
+        if repeats > 1 {
+            value.repeats = Some(repeat_stats(run_path, bench_id, repeats, &value));
         }
+        run.benchmarks.insert(bench_id.clone(), value);
     }
 
-    Ok(all_data)
+    // Compile time and artifact sizes, tracked like benchmarks of their own
+    if let Some(build) = &run_manifest.build {
+        // A build reusing a warm target directory only compiles what changed, which can't be
+        // compared with building everything from scratch
+        let kind = if build.fresh == 0 {
+            "cold"
+        } else {
+            "incremental"
+        };
+        run.benchmarks.insert(
+            format!("{}/compile_time/{}", BUILD_GROUP, kind),
+            BenchValue::exact(build.duration_secs * 1e9, CANONICAL_TIME_UNIT),
+        );
+    }
+    let metrics_path = run_path.join(METRICS_FILE);
+    if metrics_path.exists() {
+        match load_json::<BuildMetrics>(&metrics_path) {
+            Ok(metrics) => run.benchmarks.extend(metric_values(&metrics)),
+            Err(e) => warn!("Failed to parse {}: {}", metrics_path.display(), e),
+        }
+    }
+
+    run.manifest = Some(run_manifest);
+    run
+}
+
+/// The pseudo-benchmarks of the build metrics of a run
+fn metric_values(metrics: &BuildMetrics) -> Vec<(String, BenchValue)> {
+    let sizes = |kind: &str, sizes: &BTreeMap<String, u64>| {
+        sizes
+            .iter()
+            .map(|(name, &size)| {
+                (
+                    format!("{}/{}/{}", BUILD_GROUP, kind, name),
+                    BenchValue::exact(size as f64, "B"),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut values = sizes("binary_size", &metrics.binary_sizes);
+    values.extend(sizes("lib_size", &metrics.lib_sizes));
+    if let Some(count) = metrics.crate_count {
+        values.push((
+            format!("{}/crate_count", BUILD_GROUP),
+            BenchValue::exact(count as f64, "crates"),
+        ));
+    }
+    values
+}
+
+/// Add a scanned run to the data of its commit
+pub fn add_run(
+    all_data: &mut AllData,
+    commit_data: &mut CommitBenchData,
+    machine_name: String,
+    run: RunData,
+) {
+    if let Some(failure) = run.failure {
+        commit_data.failures.insert(machine_name.clone(), failure);
+    }
+    let Some(run_manifest) = run.manifest else {
+        return;
+    };
+
+    // Update machine system info (keep the latest one seen)
+    all_data
+        .machines
+        .insert(machine_name.clone(), run_manifest.system);
+
+    commit_data.machines.push(machine_name.clone());
+    commit_data.benchmarks.insert(machine_name, run.benchmarks);
+}
+
+/// Load the point estimates (with their confidence intervals) from a stored criterion result,
+/// with time units normalized to [`CANONICAL_TIME_UNIT`]
+pub fn load_bench_value(bench_path: &Path) -> Result<BenchValue> {
+    let result: BenchResult = load_json(bench_path)?;
+    Ok(BenchValue::from(result))
+}
+
+/// Combine the mean of the first repetition with the ones stored under `repeats/`
+fn repeat_stats(
+    run_path: &Path,
+    bench_id: &str,
+    repeats: usize,
+    first: &BenchValue,
+) -> RepeatStats {
+    let estimates: Vec<f64> = std::iter::once(first.estimate)
+        .chain((1..repeats).filter_map(|i| {
+            let path = run_path
+                .join(REPEATS_DIR)
+                .join(i.to_string())
+                .join(bench_id)
+                .with_extension("json");
+            load_bench_value(&path).ok().map(|v| v.estimate)
+        }))
+        .collect();
+
+    let count = estimates.len();
+    let estimate = estimates.iter().sum::<f64>() / count as f64;
+    let std_dev = if count > 1 {
+        (estimates
+            .iter()
+            .map(|e| (e - estimate).powi(2))
+            .sum::<f64>()
+            / (count - 1) as f64)
+            .sqrt()
+    } else {
+        0.0
+    };
+    RepeatStats {
+        count,
+        estimate,
+        std_dev,
+        min: estimates.iter().copied().fold(f64::INFINITY, f64::min),
+        max: estimates.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+    }
 }
@@ -0,0 +1,74 @@
+use crate::commands::bench::{BenchFilter, RunStaging};
+use crate::config::Workspace;
+use crate::harness::{read_criterion_dir, CompletedBench};
+use crate::utils::{collect_system_info, resolve_commit, save_json};
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+pub struct ImportOptions {
+    /// Commit-ish of the repo the results were measured on
+    pub commit: String,
+    /// Criterion directory to read, `<repo>/target/criterion` by default
+    pub criterion_dir: Option<PathBuf>,
+    pub force: bool,
+    pub merge: bool,
+    pub filter: BenchFilter,
+}
+
+/// Import the reports of a local `target/criterion` directory as the run of `name` on a commit.
+pub fn run(workspace: &Workspace, name: &str, options: &ImportOptions) -> Result<()> {
+    let commit_hash = resolve_commit(&workspace.repo_dir, &options.commit)?;
+    let criterion_dir = options
+        .criterion_dir
+        .clone()
+        .unwrap_or_else(|| workspace.repo_dir.join("target").join("criterion"));
+    let filter = options
+        .filter
+        .criterion_filter()
+        .map(|f| Regex::new(&f))
+        .transpose()
+        .context("invalid benchmark filter")?;
+
+    info!("reading criterion reports from {}", criterion_dir.display());
+    let reports: Vec<_> = read_criterion_dir(&criterion_dir, None)?
+        .into_iter()
+        .filter(|report| filter.as_ref().is_none_or(|f| f.is_match(&report.id)))
+        .collect();
+    if reports.is_empty() {
+        bail!("no benchmark found in {}", criterion_dir.display());
+    }
+    info!(
+        "importing {} benchmarks as run '{}' of {}",
+        reports.len(),
+        name,
+        commit_hash
+    );
+
+    // Criterion doesn't store anything about the machine, assume it is this one
+    warn!("recording the system info of this machine for the imported results");
+    let system_info = collect_system_info(None)?;
+
+    let staging = RunStaging::prepare(
+        &workspace.db_root,
+        &commit_hash,
+        name,
+        options.force,
+        options.merge || !options.filter.is_empty(),
+    )?;
+
+    let mut completed = Vec::new();
+    for report in reports {
+        let path = staging.tmp_dir.join(&report.id).with_extension("json");
+        if let Err(e) = save_json(&path, &report.data, true) {
+            staging.abort();
+            return Err(e);
+        }
+        completed.push(CompletedBench {
+            timestamp: report.timestamp(),
+            id: report.id,
+        });
+    }
+    staging.finish(system_info, completed, 1, None, None)
+}
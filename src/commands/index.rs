@@ -0,0 +1,26 @@
+use crate::index::Index;
+use anyhow::{Context, Result};
+use std::path::Path;
+use tracing::info;
+
+/// Create or refresh the SQLite index of `db/`. Once it exists, the commands scanning `db/`
+/// read through it.
+pub fn run(db_root: &Path, rebuild: bool) -> Result<()> {
+    let path = Index::path(db_root);
+    if rebuild && path.exists() {
+        std::fs::remove_file(&path)
+            .with_context(|| format!("failed to remove {}", path.display()))?;
+    }
+
+    info!("Indexing {}...", db_root.display());
+    let mut index = Index::open(db_root)?;
+    let stats = index.refresh(db_root)?;
+    info!(
+        "Indexed {} runs ({} updated, {} removed) into {}",
+        stats.runs,
+        stats.updated,
+        stats.removed,
+        path.display()
+    );
+    Ok(())
+}
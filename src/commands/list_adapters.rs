@@ -0,0 +1,30 @@
+use crate::utils::enumerate_adapters;
+use anyhow::Result;
+use tracing::warn;
+
+/// Print every wgpu adapter on this machine, indexed the same way `--adapter` expects.
+pub fn run() -> Result<()> {
+    let instance = wgpu::Instance::default();
+    let adapters = enumerate_adapters(&instance);
+    if adapters.is_empty() {
+        warn!("No wgpu adapters found.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<6} {:<40} {:<8} {:<14} driver",
+        "index", "name", "backend", "type"
+    );
+    for (i, info) in adapters.iter().enumerate() {
+        println!(
+            "{:<6} {:<40} {:<8} {:<14} {} {}",
+            i,
+            info.name,
+            info.backend.to_str(),
+            format!("{:?}", info.device_type),
+            info.driver,
+            info.driver_info
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use crate::migrations::{upgrade, CURRENT_FORMAT_VERSION};
+use crate::utils::save_json;
+use anyhow::Result;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Upgrade every run of `db/` to the current format version.
+pub fn run(db_dir: &Path, dry_run: bool) -> Result<()> {
+    if !db_dir.exists() {
+        warn!("db directory not found at {}", db_dir.display());
+        return Ok(());
+    }
+
+    info!(
+        "Migrating runs to format version {}{}...",
+        CURRENT_FORMAT_VERSION,
+        if dry_run { " (dry run)" } else { "" }
+    );
+    let (mut migrated, mut up_to_date, mut failed) = (0, 0, 0);
+
+    for entry in std::fs::read_dir(db_dir)? {
+        let path = entry?.path();
+        let commit_hash = path.file_name().unwrap().to_string_lossy().into_owned();
+        if !path.is_dir() || commit_hash.len() != 40 {
+            continue;
+        }
+
+        for run_entry in std::fs::read_dir(&path)? {
+            let run_path = run_entry?.path();
+            let run_name = run_path.file_name().unwrap().to_string_lossy().into_owned();
+            // Failed attempts and interrupted runs have no manifest to migrate
+            if !run_path.is_dir()
+                || run_name.ends_with(".tmp")
+                || (run_path.join("failure.json").exists() && !run_path.join("run.json").exists())
+            {
+                continue;
+            }
+
+            let upgrade = match upgrade(&run_path) {
+                Ok(upgrade) => upgrade,
+                Err(e) => {
+                    warn!("{}/{}: {:#}", commit_hash, run_name, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+            if upgrade.from_version == CURRENT_FORMAT_VERSION {
+                up_to_date += 1;
+                continue;
+            }
+
+            info!(
+                "{}/{}: v{} -> v{} ({})",
+                commit_hash,
+                run_name,
+                upgrade.from_version,
+                CURRENT_FORMAT_VERSION,
+                if upgrade.applied.is_empty() {
+                    "only the version changes".to_string()
+                } else {
+                    upgrade.applied.join(", ")
+                }
+            );
+            if !dry_run {
+                save_json(run_path.join("run.json"), &upgrade.manifest, true)?;
+                for path in &upgrade.obsolete {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            migrated += 1;
+        }
+    }
+
+    info!(
+        "{} {} runs, {} already up to date, {} failed.",
+        if dry_run { "Would migrate" } else { "Migrated" },
+        migrated,
+        up_to_date,
+        failed
+    );
+    Ok(())
+}
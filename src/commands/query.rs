@@ -0,0 +1,239 @@
+use crate::commands::export::{statistics, Statistic};
+use crate::commands::graph::scan_db;
+use crate::config::Workspace;
+use crate::utils::run_git;
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum QueryFormat {
+    #[default]
+    Table,
+    Json,
+    /// One line per series
+    Sparkline,
+}
+
+pub struct QueryOptions {
+    /// Glob over benchmark ids, all benchmarks if not given
+    pub bench: Option<String>,
+    /// Only these machines, all if empty
+    pub machines: Vec<String>,
+    pub stat: Statistic,
+    /// Only keep the latest points of each series
+    pub last: Option<usize>,
+    /// Revision range whose history is walked, all refs if not given
+    pub rev: Option<String>,
+    pub format: QueryFormat,
+}
+
+#[derive(Debug, Serialize)]
+struct Point {
+    commit: String,
+    date: String,
+    subject: String,
+    estimate: f64,
+    lower_bound: Option<f64>,
+    upper_bound: Option<f64>,
+}
+
+/// One benchmark on one machine, oldest commit first
+#[derive(Debug, Serialize)]
+struct Series {
+    bench_id: String,
+    machine: String,
+    statistic: &'static str,
+    unit: String,
+    points: Vec<Point>,
+}
+
+/// Print the history of one statistic for the matching benchmarks, in topological order.
+pub fn run(workspace: &Workspace, options: &QueryOptions) -> Result<()> {
+    let pattern = options.bench.as_deref().map(glob_regex).transpose()?;
+
+    info!("Scanning db/ for benchmark data...");
+    let all_data = scan_db(&workspace.db_root)?;
+
+    // Parents before children, so series read left to right in history order
+    let log = run_git(
+        &workspace.repo_dir,
+        [
+            "log",
+            "--topo-order",
+            "--reverse",
+            "--format=%H%x09%cs%x09%s",
+            options.rev.as_deref().unwrap_or("--all"),
+        ],
+    )?;
+
+    let mut series: BTreeMap<(&str, &str), Series> = BTreeMap::new();
+    for line in log.lines() {
+        let mut fields = line.splitn(3, '\t');
+        let (Some(commit), Some(date)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let subject = fields.next().unwrap_or_default();
+        let Some(data) = all_data.commits.get(commit) else {
+            continue;
+        };
+
+        for (machine, results) in &data.benchmarks {
+            if !options.machines.is_empty() && !options.machines.contains(machine) {
+                continue;
+            }
+            for (bench_id, value) in results {
+                if pattern.as_ref().is_some_and(|p| !p.is_match(bench_id)) {
+                    continue;
+                }
+                let Some(stat) = statistics(value)
+                    .into_iter()
+                    .find(|s| s.statistic == options.stat)
+                else {
+                    continue;
+                };
+                series
+                    .entry((bench_id, machine))
+                    .or_insert_with(|| Series {
+                        bench_id: bench_id.clone(),
+                        machine: machine.clone(),
+                        statistic: options.stat.name(),
+                        unit: stat.unit,
+                        points: Vec::new(),
+                    })
+                    .points
+                    .push(Point {
+                        commit: commit.to_string(),
+                        date: date.to_string(),
+                        subject: subject.to_string(),
+                        estimate: stat.estimate,
+                        lower_bound: stat.lower_bound,
+                        upper_bound: stat.upper_bound,
+                    });
+            }
+        }
+    }
+
+    let mut series: Vec<Series> = series.into_values().collect();
+    if let Some(last) = options.last {
+        for s in &mut series {
+            let skip = s.points.len().saturating_sub(last);
+            s.points.drain(..skip);
+        }
+    }
+
+    match options.format {
+        QueryFormat::Table => print_table(&series),
+        QueryFormat::Json => println!("{}", serde_json::to_string_pretty(&series)?),
+        QueryFormat::Sparkline => print_sparklines(&series),
+    }
+    info!(
+        "Found {} series with {} points",
+        series.len(),
+        series.iter().map(|s| s.points.len()).sum::<usize>()
+    );
+    Ok(())
+}
+
+/// Translate a shell-style glob (`*`, `?`) into an anchored regex
+fn glob_regex(glob: &str) -> Result<Regex> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).with_context(|| format!("invalid benchmark glob `{}`", glob))
+}
+
+fn print_table(series: &[Series]) {
+    if series.is_empty() {
+        println!("No data found.");
+        return;
+    }
+
+    for (i, s) in series.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!(
+            "{} on {} ({}, {})",
+            s.bench_id, s.machine, s.statistic, s.unit
+        );
+        println!(
+            "{:<10} {:<10} {:>14} {:>14} {:>14} {:>9}  subject",
+            "commit", "date", "estimate", "lower", "upper", "change"
+        );
+        let mut previous: Option<f64> = None;
+        for point in &s.points {
+            let bound = |b: Option<f64>| b.map_or("-".to_string(), |b| format!("{:.1}", b));
+            let change = previous.filter(|&p| p != 0.0).map_or("-".to_string(), |p| {
+                format!("{:+.2}%", (point.estimate - p) / p * 100.0)
+            });
+            println!(
+                "{:<10} {:<10} {:>14.1} {:>14} {:>14} {:>9}  {}",
+                &point.commit[..8],
+                point.date,
+                point.estimate,
+                bound(point.lower_bound),
+                bound(point.upper_bound),
+                change,
+                point.subject
+            );
+            previous = Some(point.estimate);
+        }
+    }
+}
+
+fn print_sparklines(series: &[Series]) {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    if series.is_empty() {
+        println!("No data found.");
+        return;
+    }
+
+    let id_width = series.iter().map(|s| s.bench_id.len()).max().unwrap_or(0);
+    let machine_width = series.iter().map(|s| s.machine.len()).max().unwrap_or(0);
+    for s in series {
+        let min = s
+            .points
+            .iter()
+            .map(|p| p.estimate)
+            .fold(f64::INFINITY, f64::min);
+        let max = s
+            .points
+            .iter()
+            .map(|p| p.estimate)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let line: String = s
+            .points
+            .iter()
+            .map(|p| {
+                if max > min {
+                    let level = (p.estimate - min) / (max - min) * (BARS.len() - 1) as f64;
+                    BARS[level.round() as usize]
+                } else {
+                    BARS[BARS.len() / 2]
+                }
+            })
+            .collect();
+        let last = s.points.last().map_or(0.0, |p| p.estimate);
+        println!(
+            "{:<id_width$}  {:<machine_width$}  {}  last {:.1} {} (min {:.1}, max {:.1}, {} commits)",
+            s.bench_id,
+            s.machine,
+            line,
+            last,
+            s.unit,
+            min,
+            max,
+            s.points.len()
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use crate::commands::graph::scan_db;
+use crate::common::{AllData, BenchValue, OutputFormat, BUILD_GROUP};
+use crate::config::Workspace;
+use crate::utils::run_git;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use tracing::info;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ShiftKind {
+    Regressed,
+    Improved,
+}
+
+#[derive(Debug, Serialize)]
+struct Shift {
+    /// The commit where the shift was observed
+    commit: String,
+    /// The closest earlier first-parent commit with data for the same benchmark
+    base_commit: String,
+    machine: String,
+    bench_id: String,
+    kind: ShiftKind,
+    change_percent: f64,
+    base: BenchValue,
+    head: BenchValue,
+}
+
+/// Walk the first-parent history of `branch` and flag benchmarks whose mean moved
+/// outside the confidence interval of the previous commit benchmarked on the same machine.
+pub fn run(
+    workspace: &Workspace,
+    branch: &str,
+    machine: Option<&str>,
+    threshold: f64,
+    format: OutputFormat,
+) -> Result<()> {
+    let db_root = &workspace.db_root;
+
+    info!("Scanning db/ for benchmark data...");
+    let all_data = scan_db(db_root)?;
+
+    let rev_list = run_git(
+        &workspace.repo_dir,
+        ["rev-list", "--first-parent", "--reverse", branch],
+    )?;
+    let history: Vec<&str> = rev_list.lines().map(str::trim).collect();
+    info!(
+        "Walking {} first-parent commits of {}",
+        history.len(),
+        branch
+    );
+
+    let mut machines: Vec<&String> = all_data
+        .machines
+        .keys()
+        .filter(|m| machine.is_none_or(|name| name == m.as_str()))
+        .collect();
+    machines.sort();
+
+    let shifts = find_shifts(&all_data, &history, &machines, threshold);
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&shifts)?),
+        OutputFormat::Text => print_text(&shifts),
+    }
+
+    info!(
+        "Found {} suspect changes ({} regressions)",
+        shifts.len(),
+        shifts
+            .iter()
+            .filter(|s| matches!(s.kind, ShiftKind::Regressed))
+            .count()
+    );
+    Ok(())
+}
+
+/// The shifts along `history`, oldest commit first. Values without a confidence interval
+/// can't tell noise from a real shift and are never flagged.
+fn find_shifts(
+    all_data: &AllData,
+    history: &[&str],
+    machines: &[&String],
+    threshold: f64,
+) -> Vec<Shift> {
+    let mut shifts = Vec::new();
+    for &machine in machines {
+        // bench_id -> (commit, value) of the latest commit seen so far
+        let mut previous: HashMap<&str, (&str, &BenchValue)> = HashMap::new();
+
+        for &commit in history {
+            let Some(results) = all_data
+                .commits
+                .get(commit)
+                .and_then(|data| data.benchmarks.get(machine))
+            else {
+                continue;
+            };
+
+            // Build metrics have no confidence interval to tell noise from a real shift
+            let mut bench_ids: Vec<&String> = results
+                .keys()
+                .filter(|id| !id.starts_with(&format!("{}/", BUILD_GROUP)))
+                .collect();
+            bench_ids.sort();
+            for bench_id in bench_ids {
+                let head = &results[bench_id];
+                if let Some((base_commit, base)) = previous.get(bench_id.as_str())
+                    && base.unit == head.unit
+                    && base.overlaps(head) == Some(false)
+                    && let Some(change_percent) = head.change_percent(base)
+                    && change_percent.abs() >= threshold
+                {
+                    shifts.push(Shift {
+                        commit: commit.to_string(),
+                        base_commit: base_commit.to_string(),
+                        machine: machine.clone(),
+                        bench_id: bench_id.clone(),
+                        kind: if change_percent > 0.0 {
+                            ShiftKind::Regressed
+                        } else {
+                            ShiftKind::Improved
+                        },
+                        change_percent,
+                        base: (*base).clone(),
+                        head: head.clone(),
+                    });
+                }
+                previous.insert(bench_id, (commit, head));
+            }
+        }
+    }
+    shifts
+}
+
+fn print_text(shifts: &[Shift]) {
+    if shifts.is_empty() {
+        println!("No significant changes found.");
+        return;
+    }
+
+    println!(
+        "{:<10} {:<10} {:<12} {:<40} {:>16} {:>16} {:>9}",
+        "commit", "base", "machine", "benchmark", "base mean", "head mean", "change"
+    );
+    for shift in shifts {
+        println!(
+            "{:<10} {:<10} {:<12} {:<40} {:>13.1} {:<2} {:>13.1} {:<2} {:>+8.2}% {}",
+            &shift.commit[..8],
+            &shift.base_commit[..8],
+            shift.machine,
+            shift.bench_id,
+            shift.base.estimate,
+            shift.base.unit,
+            shift.head.estimate,
+            shift.head.unit,
+            shift.change_percent,
+            match shift.kind {
+                ShiftKind::Regressed => "regressed",
+                ShiftKind::Improved => "improved",
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A criterion result, with a 1% confidence interval
+    fn criterion(estimate: f64) -> BenchValue {
+        BenchValue {
+            lower_bound: estimate * 0.99,
+            upper_bound: estimate * 1.01,
+            ..BenchValue::exact(estimate, "ns")
+        }
+    }
+
+    fn libtest(estimate: f64) -> BenchValue {
+        BenchValue {
+            no_interval: true,
+            ..BenchValue::exact(estimate, "ns")
+        }
+    }
+
+    fn add_commit(all_data: &mut AllData, commit: &str, results: [(&str, BenchValue); 2]) {
+        let data = all_data.commits.entry(commit.to_string()).or_default();
+        data.machines.push("lab".to_string());
+        data.benchmarks.insert(
+            "lab".to_string(),
+            results
+                .into_iter()
+                .map(|(id, value)| (id.to_string(), value))
+                .collect(),
+        );
+    }
+
+    #[test]
+    fn flags_shifts_outside_the_interval() {
+        let mut all_data = AllData::default();
+        for (commit, criterion_mean, libtest_median) in
+            [("a", 100.0, 100.0), ("b", 100.5, 150.0), ("c", 120.0, 50.0)]
+        {
+            add_commit(
+                &mut all_data,
+                commit,
+                [
+                    ("criterion", criterion(criterion_mean)),
+                    ("libtest", libtest(libtest_median)),
+                ],
+            );
+        }
+        let lab = "lab".to_string();
+
+        let shifts = find_shifts(&all_data, &["a", "b", "c"], &[&lab], 0.0);
+        // The libtest benchmark has no interval, whatever its change
+        assert_eq!(shifts.len(), 1);
+        assert_eq!(shifts[0].bench_id, "criterion");
+        assert_eq!(
+            (shifts[0].base_commit.as_str(), shifts[0].commit.as_str()),
+            ("b", "c")
+        );
+        assert!(matches!(shifts[0].kind, ShiftKind::Regressed));
+
+        assert!(find_shifts(&all_data, &["a", "b", "c"], &[&lab], 50.0).is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::common::{
+    BenchResult, FailureRecord, GroupComplete, OutputFormat, RunManifest, METRICS_FILE, REPEATS_DIR,
+};
+use crate::utils::load_json;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ProblemKind {
+    /// A directory of `db/` whose name isn't a 40-char commit hash
+    InvalidCommitDir,
+    /// A run directory with neither `run.json` nor `failure.json`
+    MissingManifest,
+    /// A JSON file not matching its schema
+    Unparsable,
+    /// A benchmark listed in `run.json` without its file
+    MissingFile,
+    /// A benchmark file not listed in `run.json`
+    Unlisted,
+    /// A `<name>.tmp` staging directory left over by a run that was killed
+    LeftoverStaging,
+}
+
+impl ProblemKind {
+    fn label(&self) -> &'static str {
+        match self {
+            ProblemKind::InvalidCommitDir => "invalid dir",
+            ProblemKind::MissingManifest => "no run.json",
+            ProblemKind::Unparsable => "unparsable",
+            ProblemKind::MissingFile => "missing file",
+            ProblemKind::Unlisted => "unlisted",
+            ProblemKind::LeftoverStaging => "leftover tmp",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Problem {
+    /// Relative to `db/`
+    path: String,
+    kind: ProblemKind,
+    detail: Option<String>,
+}
+
+/// Check every file of `db/` against the schema the other commands expect. The namespaces of
+/// `projects` are left to `--project`.
+pub fn run(db_root: &Path, projects: &[String], format: OutputFormat) -> Result<()> {
+    let mut validator = Validator {
+        db_root,
+        problems: Vec::new(),
+        files: 0,
+    };
+
+    info!("Validating {}...", db_root.display());
+    for path in sorted_entries(db_root)? {
+        let name = path.file_name().unwrap().to_string_lossy();
+        if !path.is_dir() || projects.iter().any(|p| *p == name) {
+            continue;
+        }
+        if name.len() != 40 || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+            validator.report(&path, ProblemKind::InvalidCommitDir, None);
+            continue;
+        }
+        for run_path in sorted_entries(&path)? {
+            if !run_path.is_dir() {
+                continue;
+            }
+            if run_path.to_string_lossy().ends_with(".tmp") {
+                validator.report(&run_path, ProblemKind::LeftoverStaging, None);
+            } else {
+                validator.check_run(&run_path)?;
+            }
+        }
+    }
+
+    let Validator {
+        problems, files, ..
+    } = validator;
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&problems)?),
+        OutputFormat::Text => print_text(&problems),
+    }
+
+    info!("Checked {} files", files);
+    if !problems.is_empty() {
+        bail!("{} problems found in db/", problems.len());
+    }
+    Ok(())
+}
+
+struct Validator<'a> {
+    db_root: &'a Path,
+    problems: Vec<Problem>,
+    files: usize,
+}
+
+impl Validator<'_> {
+    fn report(&mut self, path: &Path, kind: ProblemKind, detail: Option<String>) {
+        let path = path.strip_prefix(self.db_root).unwrap_or(path);
+        self.problems.push(Problem {
+            path: path.display().to_string(),
+            kind,
+            detail,
+        });
+    }
+
+    /// Parse `path` as `T`, reporting it if it doesn't match
+    fn check_file<T: serde::de::DeserializeOwned>(&mut self, path: &Path) -> Option<T> {
+        self.files += 1;
+        match load_json::<T>(path) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.report(path, ProblemKind::Unparsable, Some(e.to_string()));
+                None
+            }
+        }
+    }
+
+    fn check_run(&mut self, run_path: &Path) -> Result<()> {
+        let failure_path = run_path.join("failure.json");
+        let has_failure = failure_path.exists();
+        if has_failure {
+            self.check_file::<FailureRecord>(&failure_path);
+        }
+
+        let manifest_path = run_path.join("run.json");
+        let manifest = if manifest_path.exists() {
+            self.check_file::<RunManifest>(&manifest_path)
+        } else {
+            if !has_failure {
+                self.report(run_path, ProblemKind::MissingManifest, None);
+            }
+            None
+        };
+
+        let mut files = Vec::new();
+        collect_bench_files(run_path, "", true, &mut files)?;
+        let mut ids = BTreeSet::new();
+        for (id, path) in files {
+            if path.file_name().is_some_and(|name| name == "group.json") {
+                self.check_file::<GroupComplete>(&path);
+                continue;
+            }
+            self.check_file::<BenchResult>(&path);
+            ids.insert(id);
+        }
+
+        // Repetitions aren't listed in the manifest, only check that they parse
+        let repeats_dir = run_path.join(REPEATS_DIR);
+        if repeats_dir.is_dir() {
+            let mut files = Vec::new();
+            collect_bench_files(&repeats_dir, "", false, &mut files)?;
+            for (_, path) in files {
+                self.check_file::<BenchResult>(&path);
+            }
+        }
+
+        let Some(manifest) = manifest else {
+            return Ok(());
+        };
+        let listed: BTreeSet<&String> = manifest.benchmarks.iter().collect();
+        for id in &listed {
+            if !ids.contains(*id) {
+                let path = run_path.join(id).with_extension("json");
+                self.report(&path, ProblemKind::MissingFile, None);
+            }
+        }
+        for id in &ids {
+            if !listed.contains(id) {
+                let path = run_path.join(id).with_extension("json");
+                self.report(&path, ProblemKind::Unlisted, None);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collect the `<id>.json` files below `dir`. At the top of a run directory, the manifest,
+/// the failure record and the repetitions are left out.
+fn collect_bench_files(
+    dir: &Path,
+    prefix: &str,
+    run_root: bool,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<()> {
+    for path in sorted_entries(dir)? {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let id = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        if path.is_dir() {
+            if run_root && name == REPEATS_DIR {
+                continue;
+            }
+            collect_bench_files(&path, &id, false, files)?;
+        } else if let Some(id) = id.strip_suffix(".json") {
+            if run_root
+                && matches!(
+                    name.as_str(),
+                    "run.json" | "failure.json" | "system_info.json" | METRICS_FILE
+                )
+            {
+                continue;
+            }
+            files.push((id.to_string(), path));
+        }
+    }
+    Ok(())
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+fn print_text(problems: &[Problem]) {
+    if problems.is_empty() {
+        println!("No problems found.");
+        return;
+    }
+
+    for problem in problems {
+        match &problem.detail {
+            Some(detail) => println!("{:<14} {}: {}", problem.kind.label(), problem.path, detail),
+            None => println!("{:<14} {}", problem.kind.label(), problem.path),
+        }
+    }
+}
@@ -1,15 +1,87 @@
+use crate::harness::HarnessKind;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
 use wgpu::AdapterInfo;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RunManifest {
+    /// See [`CURRENT_FORMAT_VERSION`](crate::migrations::CURRENT_FORMAT_VERSION)
+    #[serde(default)]
+    pub format_version: u32,
     pub commit_hash: String,
     pub name: String,
     #[serde(flatten)]
     pub system: SystemInfo,
     /// List of benchmark IDs executed in this run
     pub benchmarks: Vec<String>,
+    /// When and on which system snapshot each benchmark was recorded, keyed by benchmark ID
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub records: BTreeMap<String, BenchRecord>,
+    /// System info snapshots of all runs merged into this one, oldest first.
+    /// The flattened `system` is the latest one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub systems: Vec<SystemInfo>,
+    /// Build step of the first run recording one, runs merged into it keep it. Unknown before
+    /// it was tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildInfo>,
+}
+
+/// The build step of a run, compiling the benchmarks before any of them runs
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BuildInfo {
+    /// Wall-clock time of `cargo bench --no-run`, in seconds
+    pub duration_secs: f64,
+    /// Only other than 0 in the `build` of a [`FailureRecord`]
+    pub exit_code: Option<i32>,
+    /// Compiler warnings emitted while building
+    pub warnings: usize,
+    /// Crates compiled by this build
+    pub compiled: usize,
+    /// Crates already up to date in the target directory
+    pub fresh: usize,
+}
+
+/// Artifacts of the build step of a run, stored as `metrics.json` next to `run.json`
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BuildMetrics {
+    /// Size in bytes of each bench executable, by bench target
+    pub binary_sizes: BTreeMap<String, u64>,
+    /// Size in bytes of the library artifacts of the workspace, by target name and extension,
+    /// e.g. `ranim.rlib`
+    pub lib_sizes: BTreeMap<String, u64>,
+    /// Number of packages in `Cargo.lock`, none if there's no lock file
+    pub crate_count: Option<usize>,
+}
+
+/// Repetition `i > 0` of a benchmark is stored at `<run_dir>/repeats/<i>/<id>.json`,
+/// the first one stays at `<run_dir>/<id>.json`
+pub const REPEATS_DIR: &str = "repeats";
+
+/// Sizes of the build artifacts of a run, see [`BuildMetrics`]
+pub const METRICS_FILE: &str = "metrics.json";
+
+/// Group of the pseudo-benchmarks describing the build of a run, like its compile time,
+/// rather than measured by the harness
+pub const BUILD_GROUP: &str = "_build";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BenchRecord {
+    /// Completion time, unknown for results recorded before this was tracked
+    pub timestamp: Option<String>,
+    /// Index into `RunManifest::systems`
+    pub system: usize,
+    /// Number of repetitions stored for this benchmark
+    #[serde(default = "default_repeats")]
+    pub repeats: usize,
+    /// Harness that produced the result, unknown for results recorded before this was tracked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub harness: Option<HarnessKind>,
+}
+
+fn default_repeats() -> usize {
+    1
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -20,7 +92,58 @@ pub struct SystemInfo {
     pub arch: String,
     pub memory: u64,
     pub cpus: Vec<CpuInfo>,
-    pub wgpu_adapter_info: AdapterInfo,
+    /// `None` on machines without any usable wgpu adapter (e.g. headless CI runners)
+    #[serde(default)]
+    pub wgpu_adapter_info: Option<AdapterInfo>,
+    /// Backend of the chosen adapter
+    #[serde(default)]
+    pub wgpu_backend: Option<wgpu::Backend>,
+    /// Whether the chosen adapter is a software one (`DeviceType::Cpu`), like the adapter wgpu
+    /// falls back to without a GPU
+    #[serde(default)]
+    pub wgpu_fallback_adapter: bool,
+    /// All adapters enumerated on the machine
+    #[serde(default)]
+    pub wgpu_adapters: Vec<AdapterInfo>,
+    /// Index of the chosen adapter in `wgpu_adapters`
+    #[serde(default)]
+    pub wgpu_adapter_index: Option<usize>,
+}
+
+/// Selects a wgpu adapter by its index in the enumeration, its backend or its name
+#[derive(Debug, Clone)]
+pub enum AdapterSelector {
+    Index(usize),
+    Backend(wgpu::Backend),
+    /// Case-insensitive substring of the adapter name
+    Name(String),
+}
+
+impl AdapterSelector {
+    pub fn matches(&self, index: usize, info: &AdapterInfo) -> bool {
+        match self {
+            AdapterSelector::Index(i) => *i == index,
+            AdapterSelector::Backend(backend) => info.backend == *backend,
+            AdapterSelector::Name(name) => info.name.to_lowercase().contains(&name.to_lowercase()),
+        }
+    }
+}
+
+impl FromStr for AdapterSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(index) = s.parse() {
+            return Ok(AdapterSelector::Index(index));
+        }
+        if let Some(backend) = wgpu::Backend::ALL
+            .into_iter()
+            .find(|b| b.to_str().eq_ignore_ascii_case(s))
+        {
+            return Ok(AdapterSelector::Backend(backend));
+        }
+        Ok(AdapterSelector::Name(s.to_string()))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -31,6 +154,69 @@ pub struct CpuInfo {
     pub frequency: u64,
 }
 
+/// Written to `failure.json` in the run directory when benchmarking a commit fails
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FailureRecord {
+    pub commit_hash: String,
+    pub name: String,
+    pub stage: FailureStage,
+    pub exit_code: Option<i32>,
+    pub error: String,
+    /// Last lines of the failing process' stderr
+    pub stderr_tail: Vec<String>,
+    pub timestamp: String,
+    /// The failed build step, for build failures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureStage {
+    Checkout,
+    Build,
+    Bench,
+}
+
+impl std::fmt::Display for FailureStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FailureStage::Checkout => "checkout",
+            FailureStage::Build => "build",
+            FailureStage::Bench => "bench",
+        })
+    }
+}
+
+// --- bench-missing queue state ---
+
+/// Persistent plan of a `bench-missing` invocation, stored under db/ so it can be resumed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BenchQueue {
+    pub name: String,
+    pub created_at: String,
+    pub entries: Vec<QueueEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueueEntry {
+    pub commit_hash: String,
+    pub message: String,
+    pub status: QueueStatus,
+    /// Error text of the last failed attempt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct CommitRecord {
     pub hash: String,
@@ -60,12 +246,189 @@ pub struct CommitBenchData {
     pub machines: Vec<String>,
     /// machine -> bench_id -> BenchValue
     pub benchmarks: HashMap<String, HashMap<String, BenchValue>>,
+    /// machine -> the last failed attempt to benchmark this commit
+    pub failures: HashMap<String, FailureRecord>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BenchValue {
     pub estimate: f64,
+    /// Lower bound of the confidence interval of the mean
+    pub lower_bound: f64,
+    /// Upper bound of the confidence interval of the mean
+    pub upper_bound: f64,
+    /// See [`BenchResult::no_interval`]
+    #[serde(default)]
+    pub no_interval: bool,
     pub unit: String,
+    pub median: Option<Estimate>,
+    pub slope: Option<Estimate>,
+    pub median_abs_dev: Option<Estimate>,
+    pub typical: Option<Estimate>,
+    /// Criterion's comparison against the previous run on the same machine
+    pub change: Option<ChangeEstimates>,
+    /// Derived from the mean, if the benchmark declares a throughput
+    pub throughput: Option<ThroughputValue>,
+    /// Spread of the mean across repeated runs (`bench --repeat`)
+    pub repeats: Option<RepeatStats>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RepeatStats {
+    /// Number of repetitions found
+    pub count: usize,
+    /// Mean of the per-repetition means
+    pub estimate: f64,
+    /// Sample standard deviation of the per-repetition means
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThroughputValue {
+    /// Elements/bytes processed by one iteration
+    pub per_iteration: u64,
+    /// "elements", "bytes" or "bytes_decimal"
+    pub unit: String,
+    /// Mean time per element/byte, in the canonical time unit
+    pub time_per_element: f64,
+    /// Elements/bytes processed per second
+    pub per_second: f64,
+}
+
+/// A point estimate with its confidence interval
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Estimate {
+    pub estimate: f64,
+    pub lower_bound: f64,
+    pub upper_bound: f64,
+    pub unit: String,
+}
+
+/// Unit all time estimates are normalized to
+pub const CANONICAL_TIME_UNIT: &str = "ns";
+
+/// Factor converting `unit` into [`CANONICAL_TIME_UNIT`], `None` for non-time units like "%"
+pub fn time_unit_scale(unit: &str) -> Option<f64> {
+    match unit {
+        "ps" => Some(1e-3),
+        "ns" => Some(1.0),
+        "us" | "µs" | "μs" => Some(1e3),
+        "ms" => Some(1e6),
+        "s" => Some(1e9),
+        _ => None,
+    }
+}
+
+impl Estimate {
+    /// Convert a time estimate into [`CANONICAL_TIME_UNIT`], leaving other units untouched
+    pub fn normalized(self) -> Estimate {
+        match time_unit_scale(&self.unit) {
+            Some(scale) => Estimate {
+                estimate: self.estimate * scale,
+                lower_bound: self.lower_bound * scale,
+                upper_bound: self.upper_bound * scale,
+                unit: CANONICAL_TIME_UNIT.to_string(),
+            },
+            None => self,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChangeEstimates {
+    /// Relative change of the mean (unit "%", as a fraction)
+    pub mean: Estimate,
+    /// Relative change of the median (unit "%", as a fraction)
+    pub median: Estimate,
+    pub change: ChangeVerdict,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeVerdict {
+    NoChange,
+    Improved,
+    Regressed,
+}
+
+impl From<BenchResult> for BenchValue {
+    fn from(result: BenchResult) -> Self {
+        let mean = result.mean.normalized();
+        // cargo-criterion reports at most one throughput per benchmark
+        let throughput = result
+            .throughput
+            .into_iter()
+            .next()
+            .filter(|t| t.per_iteration != 0 && mean.unit == CANONICAL_TIME_UNIT)
+            .map(|t| {
+                let time_per_element = mean.estimate / t.per_iteration as f64;
+                ThroughputValue {
+                    per_iteration: t.per_iteration,
+                    unit: t.unit,
+                    time_per_element,
+                    per_second: 1e9 / time_per_element,
+                }
+            });
+
+        BenchValue {
+            estimate: mean.estimate,
+            lower_bound: mean.lower_bound,
+            upper_bound: mean.upper_bound,
+            no_interval: result.no_interval,
+            unit: mean.unit,
+            median: Some(result.median.normalized()),
+            slope: result.slope.map(Estimate::normalized),
+            median_abs_dev: result.median_abs_dev.map(Estimate::normalized),
+            typical: Some(result.typical.normalized()),
+            change: result.change,
+            throughput,
+            repeats: None,
+        }
+    }
+}
+
+impl BenchValue {
+    /// A value without spread, like the compile time of a run
+    pub fn exact(estimate: f64, unit: &str) -> Self {
+        BenchValue {
+            estimate,
+            lower_bound: estimate,
+            upper_bound: estimate,
+            no_interval: false,
+            unit: unit.to_string(),
+            median: None,
+            slope: None,
+            median_abs_dev: None,
+            typical: None,
+            change: None,
+            throughput: None,
+            repeats: None,
+        }
+    }
+
+    /// Whether the confidence intervals of the two values overlap, none if either has no
+    /// interval to tell
+    pub fn overlaps(&self, other: &BenchValue) -> Option<bool> {
+        (!self.no_interval && !other.no_interval).then_some(
+            self.lower_bound <= other.upper_bound && other.lower_bound <= self.upper_bound,
+        )
+    }
+
+    /// Relative change of the estimate from `base` to `self`, in percent, none for a zero
+    /// baseline
+    pub fn change_percent(&self, base: &BenchValue) -> Option<f64> {
+        (base.estimate != 0.0).then(|| (self.estimate - base.estimate) / base.estimate * 100.0)
+    }
+}
+
+// --- Reports ---
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
 }
 
 // --- Criterion output parsing ---
@@ -73,7 +436,7 @@ pub struct BenchValue {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "reason", rename_all = "kebab-case")]
 pub enum BenchmarkEvent {
-    BenchmarkComplete(BenchmarkComplete),
+    BenchmarkComplete(Box<BenchmarkComplete>),
     GroupComplete(GroupComplete),
 }
 
@@ -81,7 +444,45 @@ pub enum BenchmarkEvent {
 pub struct BenchmarkComplete {
     pub id: String,
     #[serde(flatten)]
-    pub data: HashMap<String, serde_json::Value>,
+    pub result: BenchResult,
+}
+
+/// A benchmark result as stored in `<id>.json`: cargo-criterion's `benchmark-complete`
+/// message without its `id`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BenchResult {
+    /// Where criterion wrote its report on the machine that ran it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report_directory: Option<String>,
+    /// Number of iterations of each sample
+    pub iteration_count: Vec<u64>,
+    /// Total time of each sample, in `unit`
+    pub measured_values: Vec<f64>,
+    pub unit: String,
+    /// Missing in results recorded by older cargo-criterion versions
+    #[serde(default)]
+    pub throughput: Vec<Throughput>,
+    pub typical: Estimate,
+    pub mean: Estimate,
+    pub median: Estimate,
+    #[serde(default)]
+    pub median_abs_dev: Option<Estimate>,
+    #[serde(default)]
+    pub slope: Option<Estimate>,
+    /// Change from the previous run on the same machine, as judged by criterion
+    #[serde(default)]
+    pub change: Option<ChangeEstimates>,
+    /// Set by harnesses that don't estimate a confidence interval, like libtest: the bounds
+    /// then only repeat the estimate
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_interval: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Throughput {
+    pub per_iteration: u64,
+    /// "elements", "bytes" or "bytes_decimal"
+    pub unit: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
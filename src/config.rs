@@ -0,0 +1,471 @@
+use crate::harness::{CargoCommand, HarnessKind};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE_NAME: &str = "ranim-bench.toml";
+
+/// Project configuration, read from `ranim-bench.toml` in the current directory or one of its
+/// parents. Paths are relative to the directory of the file.
+///
+/// The top-level settings describe the default project, stored as `<db>/<hash>/<machine>`.
+/// Other projects are declared in `[projects.<name>]`, selected with `--project <name>` and
+/// stored as `<db>/<name>/<hash>/<machine>`.
+///
+/// ```toml
+/// repo = "ranim"
+/// benches = "benches" # relative to `repo`
+/// db = "db"
+/// output_dir = "web/public"
+/// machine = "lab"
+/// remote = "origin"
+/// branch = "main"
+/// harness = "cargo-criterion"
+/// harness_command = ["nix", "develop", "-c", "cargo"]
+///
+/// [env]
+/// RANIM_BENCH_QUICK = "1"
+///
+/// [selection]
+/// branch = "origin/main"
+///
+/// [machines.lab]
+/// harness = "cargo-bench"
+///
+/// [machines.lab.env]
+/// WGPU_BACKEND = "vulkan"
+///
+/// [machines.lab.selection]
+/// first_parent = true
+/// every = 2
+///
+/// [projects.other]
+/// repo = "../other"
+/// branch = "master"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Checkout of the benchmarked repository [default: ranim]
+    pub repo: Option<PathBuf>,
+    /// Directory `cargo bench` runs in, relative to `repo` [default: benches]
+    pub benches: Option<PathBuf>,
+    /// Benchmark database [default: db]
+    pub db: Option<PathBuf>,
+    /// Where `graph` writes the frontend data [default: web/public]
+    pub output_dir: Option<PathBuf>,
+    /// Default machine/run name of the commands taking `--name`
+    pub machine: Option<String>,
+    /// Remote fetched before selecting commits [default: origin]
+    pub remote: Option<String>,
+    /// Branch of `remote` whose history is tracked [default: main]
+    pub branch: Option<String>,
+    /// Default benchmark harness for all machines
+    pub harness: Option<HarnessKind>,
+    /// Program run instead of `cargo` by the harness, with its leading arguments
+    pub harness_command: Option<Vec<String>>,
+    /// Environment of the benchmark process for all machines
+    pub env: BTreeMap<String, String>,
+    /// Default commit selection of `bench-missing` for all machines
+    pub selection: SelectionConfig,
+    /// Per-machine overrides, keyed by machine/run name
+    pub machines: HashMap<String, MachineConfig>,
+    /// Other projects sharing the db, keyed by project name
+    pub projects: HashMap<String, ProjectConfig>,
+    /// The project these settings were resolved for, none for the default project
+    #[serde(skip)]
+    pub project: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    pub harness: Option<HarnessKind>,
+    pub harness_command: Option<Vec<String>>,
+    /// Added to the global environment, overriding its variables
+    pub env: BTreeMap<String, String>,
+    pub selection: SelectionConfig,
+}
+
+/// A project besides the default one. The settings of the repository (`benches`, `remote`,
+/// `branch`) have their own defaults, the others fall back to the top-level ones.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub repo: Option<PathBuf>,
+    pub benches: Option<PathBuf>,
+    /// [default: `<output_dir>/<project>`]
+    pub output_dir: Option<PathBuf>,
+    pub remote: Option<String>,
+    pub branch: Option<String>,
+    pub harness: Option<HarnessKind>,
+    pub harness_command: Option<Vec<String>>,
+    /// Added to the top-level environment, overriding its variables
+    pub env: BTreeMap<String, String>,
+    pub selection: SelectionConfig,
+}
+
+/// Overrides of the paths of `ranim-bench.toml`, relative to the current directory
+#[derive(Debug, Default, clap::Args)]
+pub struct WorkspaceArgs {
+    /// Config file [default: ranim-bench.toml in the current directory or one of its parents]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Project of the config to work on [default: the top-level one]
+    #[arg(long, global = true)]
+    pub project: Option<String>,
+    /// Checkout of the benchmarked repository
+    #[arg(long, global = true)]
+    pub repo: Option<PathBuf>,
+    /// Directory `cargo bench` runs in, relative to the repository
+    #[arg(long, global = true)]
+    pub benches: Option<PathBuf>,
+    /// Benchmark database, holding every project
+    #[arg(long, global = true)]
+    pub db: Option<PathBuf>,
+    /// Where `graph` writes the frontend data
+    #[arg(long, global = true)]
+    pub output_dir: Option<PathBuf>,
+}
+
+/// The directories and refs the commands work on, from the config file and the CLI
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub repo_dir: PathBuf,
+    pub benches_dir: PathBuf,
+    /// The runs of the project, `<db>/<project>` for named projects
+    pub db_root: PathBuf,
+    /// Namespaces of the named projects inside `db_root`, when working on the default project
+    pub projects: Vec<String>,
+    pub output_dir: PathBuf,
+    pub remote: String,
+    /// The tracked branch as a remote-tracking ref, e.g. `origin/main`
+    pub branch: String,
+}
+
+/// Which commits `bench-missing` considers. Every field is optional so the CLI, the
+/// machine section and the global section can be layered on top of each other.
+#[derive(Debug, Default, Clone, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectionConfig {
+    /// Branch whose history is searched [default: the tracked branch, origin/main]
+    #[arg(long)]
+    pub branch: Option<String>,
+    /// Revision range to search instead of the branch, e.g. `v0.1.0..origin/main`
+    #[arg(long)]
+    pub range: Option<String>,
+    /// Also select tagged commits
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub tags: Option<bool>,
+    /// Only follow the first parent of merge commits
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub first_parent: Option<bool>,
+    /// Only consider commits more recent than this date (anything `git log --since` accepts)
+    #[arg(long)]
+    pub since: Option<String>,
+    /// Only keep every N-th selected commit, counting from the oldest so the picks stay put
+    /// as new commits land
+    #[arg(long)]
+    pub every: Option<usize>,
+    /// Select commits whose subject matches this regex
+    /// [default: `\(#\d+\)$` unless --tags is given]
+    #[arg(long)]
+    pub subject_regex: Option<String>,
+}
+
+impl SelectionConfig {
+    /// Fill the unset fields of `self` from `fallback`
+    pub fn or(self, fallback: &SelectionConfig) -> SelectionConfig {
+        SelectionConfig {
+            branch: self.branch.or_else(|| fallback.branch.clone()),
+            range: self.range.or_else(|| fallback.range.clone()),
+            tags: self.tags.or(fallback.tags),
+            first_parent: self.first_parent.or(fallback.first_parent),
+            since: self.since.or_else(|| fallback.since.clone()),
+            every: self.every.or(fallback.every),
+            subject_regex: self
+                .subject_regex
+                .or_else(|| fallback.subject_regex.clone()),
+        }
+    }
+}
+
+impl Workspace {
+    /// The same workspace with the repository checked out in `dir`, e.g. a worktree
+    pub fn at_checkout(&self, dir: &Path) -> Workspace {
+        let benches = self
+            .benches_dir
+            .strip_prefix(&self.repo_dir)
+            .unwrap_or(&self.benches_dir);
+        Workspace {
+            repo_dir: dir.to_path_buf(),
+            benches_dir: dir.join(benches),
+            ..self.clone()
+        }
+    }
+}
+
+impl Config {
+    /// Load `path`, or else the config file of the current directory or of its closest parent
+    /// having one. Returns the directory the paths of the config are relative to, which is the
+    /// current directory if there is no config file.
+    pub fn find(path: Option<&Path>) -> Result<(PathBuf, Config)> {
+        let current_dir = std::env::current_dir()?;
+        let path = match path {
+            Some(path) => current_dir.join(path),
+            None => match current_dir
+                .ancestors()
+                .map(|dir| dir.join(CONFIG_FILE_NAME))
+                .find(|path| path.exists())
+            {
+                Some(path) => path,
+                None => return Ok((current_dir, Config::default())),
+            },
+        };
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let config = toml::from_str(&content)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        Ok((path.parent().unwrap().to_path_buf(), config))
+    }
+
+    /// Resolve the paths of the config relative to `root_dir`, the ones given on the command
+    /// line taking precedence
+    pub fn workspace(&self, root_dir: &Path, args: &WorkspaceArgs) -> Result<Workspace> {
+        let current_dir = std::env::current_dir()?;
+        let path = |cli: &Option<PathBuf>, config: &Option<PathBuf>, default: &str| match cli {
+            Some(path) => current_dir.join(path),
+            None => root_dir.join(config.as_deref().unwrap_or(Path::new(default))),
+        };
+        let mut db_root = path(&args.db, &self.db, "db");
+        let mut output_dir = path(&args.output_dir, &self.output_dir, "web/public");
+        let projects = match &self.project {
+            Some(project) => {
+                db_root.push(project);
+                if args.output_dir.is_none() && self.output_dir.is_none() {
+                    output_dir.push(project);
+                }
+                Vec::new()
+            }
+            None => self.projects.keys().cloned().collect(),
+        };
+        let repo_dir = path(&args.repo, &self.repo, "ranim");
+        let benches = args
+            .benches
+            .as_deref()
+            .or(self.benches.as_deref())
+            .unwrap_or(Path::new("benches"));
+        let remote = self.remote.clone().unwrap_or_else(|| "origin".to_string());
+        Ok(Workspace {
+            benches_dir: repo_dir.join(benches),
+            repo_dir,
+            db_root,
+            projects,
+            output_dir,
+            branch: format!("{}/{}", remote, self.branch.as_deref().unwrap_or("main")),
+            remote,
+        })
+    }
+
+    /// The settings of a named project, layered over the top-level ones
+    pub fn for_project(mut self, name: &str) -> Result<Config> {
+        let Some(project) = self.projects.remove(name) else {
+            let mut known: Vec<&String> = self.projects.keys().collect();
+            known.sort();
+            bail!("unknown project `{}`, known projects: {:?}", name, known);
+        };
+        // Project namespaces live next to the commit directories of the default project
+        if name.len() == 40 || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("invalid project name `{}`", name);
+        }
+        let Some(repo) = project.repo else {
+            bail!("project `{}` has no `repo`", name);
+        };
+
+        let output_dir = self.output_dir.take().map(|dir| dir.join(name));
+        Ok(Config {
+            repo: Some(repo),
+            benches: project.benches,
+            db: self.db,
+            output_dir: project.output_dir.or(output_dir),
+            machine: self.machine,
+            remote: project.remote,
+            branch: project.branch,
+            harness: project.harness.or(self.harness),
+            harness_command: project.harness_command.or(self.harness_command),
+            env: self.env.into_iter().chain(project.env).collect(),
+            selection: project.selection.or(&self.selection),
+            machines: self.machines,
+            projects: HashMap::new(),
+            project: Some(name.to_string()),
+        })
+    }
+
+    /// The machine/run name given on the command line, else the configured one
+    pub fn machine_name(&self, name: Option<String>) -> Result<String> {
+        match name.or_else(|| self.machine.clone()) {
+            Some(name) => Ok(name),
+            None => bail!(
+                "no machine/run name, pass --name or set `machine` in {}",
+                CONFIG_FILE_NAME
+            ),
+        }
+    }
+
+    /// The selection policy of a machine, layered over the global one
+    pub fn selection_for(&self, name: &str) -> SelectionConfig {
+        match self.machines.get(name) {
+            Some(machine) => machine.selection.clone().or(&self.selection),
+            None => self.selection.clone(),
+        }
+    }
+
+    /// The harness of a machine, falling back to the global one and then cargo-criterion
+    pub fn harness_for(&self, name: &str) -> HarnessKind {
+        self.machines
+            .get(name)
+            .and_then(|machine| machine.harness)
+            .or(self.harness)
+            .unwrap_or_default()
+    }
+
+    /// The program run instead of `cargo` on a machine
+    pub fn harness_command_for(&self, name: &str) -> CargoCommand {
+        self.machines
+            .get(name)
+            .and_then(|machine| machine.harness_command.clone())
+            .or_else(|| self.harness_command.clone())
+            .map(CargoCommand::new)
+            .unwrap_or_default()
+    }
+
+    /// The environment of the benchmark process on a machine
+    pub fn env_for(&self, name: &str) -> Vec<(String, String)> {
+        let mut env = self.env.clone();
+        if let Some(machine) = self.machines.get(name) {
+            env.extend(machine.env.clone());
+        }
+        env.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Config {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn selection_layers_field_by_field() {
+        let config = parse(
+            r#"
+            [selection]
+            branch = "origin/main"
+            every = 4
+            since = "1 year ago"
+
+            [machines.lab.selection]
+            every = 2
+            first_parent = true
+            "#,
+        );
+
+        let lab = config.selection_for("lab");
+        assert_eq!(lab.branch.as_deref(), Some("origin/main"));
+        assert_eq!(lab.since.as_deref(), Some("1 year ago"));
+        assert_eq!(lab.every, Some(2));
+        assert_eq!(lab.first_parent, Some(true));
+
+        let cli = SelectionConfig {
+            every: Some(1),
+            ..Default::default()
+        };
+        let selection = cli.or(&lab);
+        assert_eq!(selection.every, Some(1));
+        assert_eq!(selection.first_parent, Some(true));
+
+        // Machines without a section get the global selection as is
+        assert_eq!(config.selection_for("laptop").every, Some(4));
+        assert_eq!(config.selection_for("laptop").first_parent, None);
+    }
+
+    #[test]
+    fn projects_inherit_everything_but_the_repository() {
+        let config = parse(
+            r#"
+            repo = "ranim"
+            benches = "benches"
+            output_dir = "web/public"
+            machine = "lab"
+            branch = "main"
+            harness = "cargo-bench"
+
+            [env]
+            QUICK = "1"
+            BACKEND = "vulkan"
+
+            [selection]
+            every = 4
+
+            [projects.other]
+            repo = "../other"
+            branch = "master"
+
+            [projects.other.env]
+            BACKEND = "gl"
+
+            [projects.other.selection]
+            first_parent = true
+            "#,
+        );
+
+        let other = config.for_project("other").unwrap();
+        assert_eq!(other.project.as_deref(), Some("other"));
+        assert_eq!(other.repo.as_deref(), Some(Path::new("../other")));
+        assert_eq!(other.benches, None);
+        assert_eq!(other.branch.as_deref(), Some("master"));
+        assert_eq!(other.remote, None);
+        assert_eq!(other.machine.as_deref(), Some("lab"));
+        assert_eq!(other.harness, Some(HarnessKind::CargoBench));
+        assert_eq!(
+            other.output_dir.as_deref(),
+            Some(Path::new("web/public/other"))
+        );
+        assert_eq!(other.env["QUICK"], "1");
+        assert_eq!(other.env["BACKEND"], "gl");
+        assert_eq!(other.selection.every, Some(4));
+        assert_eq!(other.selection.first_parent, Some(true));
+        assert!(other.projects.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_or_incomplete_projects() {
+        let config = parse(
+            r#"
+            [projects.other]
+            repo = "../other"
+            "#,
+        );
+        let err = config.for_project("missing").unwrap_err().to_string();
+        assert!(err.contains("unknown project `missing`"), "{}", err);
+
+        let config = parse("[projects.other]\nbenches = \"benches\"");
+        let err = config.for_project("other").unwrap_err().to_string();
+        assert!(err.contains("has no `repo`"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        for content in [
+            "machnie = \"lab\"",
+            "[machines.lab]\nharnes = \"cargo-bench\"",
+            "[projects.other]\nrepo = \"../other\"\nbrnach = \"master\"",
+            "[selection]\nevery_n = 2",
+        ] {
+            assert!(toml::from_str::<Config>(content).is_err(), "{}", content);
+        }
+    }
+}
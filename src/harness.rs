@@ -0,0 +1,1015 @@
+use crate::common::{
+    BenchResult, BenchmarkEvent, BuildInfo, BuildMetrics, Estimate, FailureStage, Throughput,
+};
+use crate::utils::{load_json, save_json};
+use anyhow::{bail, Context, Result};
+use indicatif::ProgressStyle;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::io::{BufRead, BufReader};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// How the benchmark suite is run. Whatever the harness, each benchmark is stored as
+/// `<id>.json` in the layout of cargo-criterion's `benchmark-complete` message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HarnessKind {
+    /// `cargo criterion --message-format=json`
+    #[default]
+    CargoCriterion,
+    /// `cargo bench`, reading criterion's reports from `target/criterion`
+    CargoBench,
+    /// `cargo bench -- -Z unstable-options --format json` for libtest `#[bench]` (nightly only)
+    Libtest,
+}
+
+impl HarnessKind {
+    pub fn harness(self) -> Box<dyn Harness> {
+        match self {
+            HarnessKind::CargoCriterion => Box::new(CargoCriterion),
+            HarnessKind::CargoBench => Box::new(CargoBench),
+            HarnessKind::Libtest => Box::new(Libtest),
+        }
+    }
+}
+
+pub trait Harness {
+    /// Run the benchmarks of `benches_dir`, writing every completed one to
+    /// `<output_dir>/<id>.json`. `filter` is a regex on the benchmark IDs.
+    fn run(
+        &self,
+        cargo: &CargoCommand,
+        benches_dir: &Path,
+        output_dir: &Path,
+        envs: &[(String, String)],
+        filter: Option<&str>,
+    ) -> Result<Vec<CompletedBench>>;
+}
+
+/// The program standing for `cargo`, with its leading arguments (e.g. `nix develop -c cargo`)
+#[derive(Debug, Clone)]
+pub struct CargoCommand(Vec<String>);
+
+impl Default for CargoCommand {
+    fn default() -> Self {
+        CargoCommand(vec!["cargo".to_string()])
+    }
+}
+
+impl CargoCommand {
+    /// Plain `cargo` if `args` is empty
+    pub fn new(args: Vec<String>) -> Self {
+        if args.is_empty() {
+            CargoCommand::default()
+        } else {
+            CargoCommand(args)
+        }
+    }
+
+    /// A cargo invocation in `dir`, with `envs` set
+    fn command(&self, dir: &Path, envs: &[(String, String)]) -> Command {
+        let mut cmd = Command::new(&self.0[0]);
+        cmd.args(&self.0[1..])
+            .current_dir(dir)
+            .envs(envs.iter().map(|(k, v)| (k, v)));
+        cmd
+    }
+}
+
+/// A benchmark completed by the harness
+pub struct CompletedBench {
+    pub id: String,
+    pub timestamp: String,
+}
+
+/// How far file modification times may lag behind [`SystemTime::now`]
+const MTIME_SLACK: Duration = Duration::from_secs(1);
+
+/// Number of stderr lines kept for `failure.json`
+const STDERR_TAIL_LINES: usize = 50;
+
+/// A failed external process, with what's needed to write a [`FailureRecord`]
+///
+/// [`FailureRecord`]: crate::common::FailureRecord
+#[derive(Debug)]
+pub struct StageError {
+    pub stage: FailureStage,
+    pub exit_code: Option<i32>,
+    pub stderr_tail: Vec<String>,
+    /// What the build got through, for the build stage
+    pub build: Option<BuildInfo>,
+}
+
+impl std::fmt::Display for StageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed with code {:?}", self.stage, self.exit_code)
+    }
+}
+
+impl std::error::Error for StageError {}
+
+/// Process ids of the harness processes running, see [`terminate_running`]
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Terminate the harness processes running, with the benchmark binaries they started, for a
+/// signal handler. On Unix, each of them leads a process group of its own: the group can be
+/// signaled as a whole, but a Ctrl-C in the terminal no longer reaches it by itself.
+pub fn terminate_running() {
+    #[cfg(unix)]
+    for &pid in RUNNING.lock().unwrap().iter() {
+        // SAFETY: kill doesn't touch memory
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+        }
+    }
+}
+
+struct ChildGuard(Child);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let pid = self.0.id();
+        RUNNING.lock().unwrap().retain(|&p| p != pid);
+        let _ = self.0.kill();
+    }
+}
+
+impl Deref for ChildGuard {
+    type Target = Child;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for ChildGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// A running `cargo` invocation, its stderr driving a spinner
+struct HarnessProcess {
+    child: ChildGuard,
+    stdout: BufReader<ChildStdout>,
+    stderr_thread: JoinHandle<VecDeque<String>>,
+}
+
+impl HarnessProcess {
+    fn spawn(mut cmd: Command) -> Result<Self> {
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        let child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to spawn {:?}", cmd))?;
+        RUNNING.lock().unwrap().push(child.id());
+        let mut child = ChildGuard(child);
+
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        // Parse cargo/criterion stderr to drive a spinner showing current benchmark status
+        let stderr = child.stderr.take().unwrap();
+        let stderr_thread = thread::spawn(move || {
+            let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+            let reader = BufReader::new(stderr);
+            let spinner_span = tracing::info_span!("criterion");
+            spinner_span
+                .pb_set_style(&ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+            let _guard = spinner_span.enter();
+
+            for line in reader.lines() {
+                match line {
+                    Ok(line) if line.is_empty() => {}
+                    Ok(line) => {
+                        // Criterion outputs lines like "Benchmarking eval/eval_static_squares/10: Warming up for 3.0000 s"
+                        if line.starts_with("Benchmarking ") {
+                            spinner_span.pb_set_message(&line);
+                        } else {
+                            info!(target: "cargo", "{}", line);
+                        }
+                        if tail.len() == STDERR_TAIL_LINES {
+                            tail.pop_front();
+                        }
+                        tail.push_back(line);
+                    }
+                    _ => {}
+                }
+            }
+            tail
+        });
+
+        Ok(HarnessProcess {
+            child,
+            stdout,
+            stderr_thread,
+        })
+    }
+
+    /// Lines of stdout until it's closed. A line that isn't UTF-8 is read lossily instead of
+    /// ending the loop, which would leave the child blocked on a full pipe.
+    fn stdout_lines(&mut self) -> impl Iterator<Item = String> + '_ {
+        (&mut self.stdout)
+            .split(b'\n')
+            .map_while(Result::ok)
+            .map(|line| {
+                String::from_utf8_lossy(&line)
+                    .trim_end_matches('\r')
+                    .to_string()
+            })
+    }
+
+    /// Wait for the process to exit, turning a failure into a [`StageError`] of `stage`.
+    /// `diagnostics` are output lines that didn't go to stderr but belong to its tail.
+    fn wait(mut self, stage: FailureStage, diagnostics: Vec<String>) -> Result<ExitStatus> {
+        let res = self.child.wait()?;
+        if res.success() {
+            return Ok(res);
+        }
+        let stderr = self.stderr_thread.join().unwrap_or_default();
+        let mut stderr_tail: Vec<String> = diagnostics.into_iter().chain(stderr).collect();
+        stderr_tail.drain(..stderr_tail.len().saturating_sub(STDERR_TAIL_LINES));
+        Err(StageError {
+            stage,
+            exit_code: res.code(),
+            stderr_tail,
+            build: None,
+        }
+        .into())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum CargoMessage {
+    CompilerMessage {
+        message: CompilerDiagnostic,
+    },
+    CompilerArtifact {
+        package_id: String,
+        target: ArtifactTarget,
+        filenames: Vec<PathBuf>,
+        executable: Option<PathBuf>,
+        fresh: bool,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ArtifactTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CompilerDiagnostic {
+    level: String,
+    rendered: Option<String>,
+    /// Empty for the summaries like "2 warnings emitted"
+    spans: Vec<Value>,
+}
+
+/// Compile the benchmarks without running them, the same way every harness builds them, and
+/// measure what was built
+pub fn build(
+    cargo: &CargoCommand,
+    benches_dir: &Path,
+    envs: &[(String, String)],
+) -> Result<(BuildInfo, BuildMetrics)> {
+    let started = Instant::now();
+    let mut cmd = cargo.command(benches_dir, envs);
+    cmd.args(["bench", "--no-run", "--message-format=json"]);
+    let mut process = HarnessProcess::spawn(cmd)?;
+
+    let mut output = BuildOutput::default();
+    for line in process.stdout_lines() {
+        output.add_line(&line);
+    }
+    let BuildOutput {
+        warnings,
+        compiled,
+        fresh,
+        errors,
+        mut metrics,
+        ..
+    } = output;
+    let status = process.wait(FailureStage::Build, errors);
+    let info = BuildInfo {
+        duration_secs: started.elapsed().as_secs_f64(),
+        exit_code: match &status {
+            Ok(status) => status.code(),
+            Err(e) => e.downcast_ref::<StageError>().and_then(|e| e.exit_code),
+        },
+        warnings,
+        compiled,
+        fresh,
+    };
+    if let Err(mut e) = status {
+        if let Some(stage_err) = e.downcast_mut::<StageError>() {
+            stage_err.build = Some(info);
+        }
+        return Err(e);
+    }
+
+    metrics.crate_count = match benches_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.exists())
+    {
+        Some(path) => Some(lock_package_count(&path)?),
+        None => {
+            warn!("no Cargo.lock found above {}", benches_dir.display());
+            None
+        }
+    };
+    Ok((info, metrics))
+}
+
+/// What [`build`] gathers from the output of `cargo --message-format=json`
+#[derive(Default)]
+struct BuildOutput {
+    warnings: usize,
+    compiled: usize,
+    fresh: usize,
+    /// Lines of the rendered errors
+    errors: Vec<String>,
+    metrics: BuildMetrics,
+    /// Rendered diagnostics already logged
+    seen: HashSet<String>,
+}
+
+impl BuildOutput {
+    /// Account for a line of output, ignoring the ones that aren't cargo messages
+    fn add_line(&mut self, line: &str) {
+        let Ok(message) = serde_json::from_str::<CargoMessage>(line) else {
+            return;
+        };
+        match message {
+            CargoMessage::CompilerMessage { message } => {
+                // A crate built for several targets repeats its diagnostics, count them once
+                if let Some(rendered) = &message.rendered {
+                    if !self.seen.insert(rendered.clone()) {
+                        return;
+                    }
+                    info!(target: "cargo", "{}", rendered.trim_end());
+                }
+                match message.level.as_str() {
+                    "warning" if !message.spans.is_empty() => self.warnings += 1,
+                    "error" => self.errors.extend(
+                        message
+                            .rendered
+                            .iter()
+                            .flat_map(|r| r.lines().map(str::to_string)),
+                    ),
+                    _ => {}
+                }
+            }
+            CargoMessage::CompilerArtifact {
+                package_id,
+                target,
+                filenames,
+                executable,
+                fresh: is_fresh,
+            } => {
+                if is_fresh {
+                    self.fresh += 1;
+                } else {
+                    self.compiled += 1;
+                }
+                // Only the packages of the workspace, not the dependencies from registries
+                if !package_id.contains("path+file://") {
+                    return;
+                }
+                let is_bench = target.kind.iter().any(|k| k == "bench");
+                let is_lib = target.kind.iter().any(|k| k.ends_with("lib"));
+                match executable {
+                    Some(executable) if is_bench => {
+                        if let Some(size) = file_size(&executable) {
+                            self.metrics.binary_sizes.insert(target.name, size);
+                        }
+                    }
+                    // The unit tests of a library
+                    Some(_) => {}
+                    None if is_lib => {
+                        for path in &filenames {
+                            // The .rmeta files only hold metadata for dependents
+                            let Some(extension) = path.extension().filter(|e| *e != "rmeta") else {
+                                continue;
+                            };
+                            // File names carry a hash that changes between builds
+                            let name = format!("{}.{}", target.name, extension.to_string_lossy());
+                            if let Some(size) = file_size(path) {
+                                self.metrics.lib_sizes.insert(name, size);
+                            }
+                        }
+                    }
+                    None => {}
+                }
+            }
+            CargoMessage::Other => {}
+        }
+    }
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Some(metadata.len()),
+        Err(e) => {
+            warn!("failed to read the size of {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Number of `[[package]]` entries of a `Cargo.lock`
+fn lock_package_count(path: &Path) -> Result<usize> {
+    #[derive(Deserialize)]
+    struct Lock {
+        #[serde(default)]
+        package: Vec<toml::Value>,
+    }
+
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let lock: Lock =
+        toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))?;
+    Ok(lock.package.len())
+}
+
+struct CargoCriterion;
+
+impl Harness for CargoCriterion {
+    fn run(
+        &self,
+        cargo: &CargoCommand,
+        benches_dir: &Path,
+        output_dir: &Path,
+        envs: &[(String, String)],
+        filter: Option<&str>,
+    ) -> Result<Vec<CompletedBench>> {
+        let mut cmd = cargo.command(benches_dir, envs);
+        cmd.arg("criterion").arg("--message-format=json");
+        if let Some(filter) = filter {
+            info!("only running benchmarks matching `{}`", filter);
+            cmd.arg("--").arg(filter);
+        }
+        let mut process = HarnessProcess::spawn(cmd)?;
+
+        let mut completed = Vec::new();
+        for line in process.stdout_lines() {
+            let Some(event) = parse_criterion_line(&line) else {
+                continue;
+            };
+            match event {
+                BenchmarkEvent::BenchmarkComplete(evt) => {
+                    info!("benchmark `{}` complete.", evt.id);
+                    save_json(
+                        output_dir.join(&evt.id).with_extension("json"),
+                        &evt.result,
+                        true,
+                    )?;
+                    completed.push(CompletedBench {
+                        id: evt.id,
+                        timestamp: chrono::Local::now().to_rfc3339(),
+                    });
+                }
+                BenchmarkEvent::GroupComplete(evt) => {
+                    info!(
+                        "benchmark group `{} {:?}` complete.",
+                        evt.group_name, evt.benchmarks
+                    );
+                    save_json(
+                        output_dir
+                            .join(&evt.group_name)
+                            .join("group")
+                            .with_extension("json"),
+                        &evt,
+                        true,
+                    )?;
+                }
+            }
+        }
+
+        process.wait(FailureStage::Bench, Vec::new())?;
+        Ok(completed)
+    }
+}
+
+/// A message of cargo-criterion's JSON output. Other output and messages of unknown kinds are
+/// skipped, a known message that doesn't parse is warned about rather than silently lost.
+fn parse_criterion_line(line: &str) -> Option<BenchmarkEvent> {
+    let value: Value = serde_json::from_str(line).ok()?;
+    let reason = value.get("reason")?.as_str()?;
+    if !matches!(reason, "benchmark-complete" | "group-complete") {
+        return None;
+    }
+    match serde_json::from_str(line) {
+        Ok(event) => Some(event),
+        Err(e) => {
+            warn!("failed to parse a `{}` message: {}\n{}", reason, e, line);
+            None
+        }
+    }
+}
+
+struct CargoBench;
+
+impl Harness for CargoBench {
+    fn run(
+        &self,
+        cargo: &CargoCommand,
+        benches_dir: &Path,
+        output_dir: &Path,
+        envs: &[(String, String)],
+        filter: Option<&str>,
+    ) -> Result<Vec<CompletedBench>> {
+        // File timestamps come from a coarser clock, which can lag behind `now()`
+        let started = SystemTime::now() - MTIME_SLACK;
+        let mut cmd = cargo.command(benches_dir, envs);
+        cmd.arg("bench");
+        if let Some(filter) = filter {
+            info!("only running benchmarks matching `{}`", filter);
+            cmd.arg("--").arg(filter);
+        }
+        let mut process = HarnessProcess::spawn(cmd)?;
+        // Criterion prints the results to stdout, the progress to stderr
+        for line in process.stdout_lines() {
+            if !line.is_empty() {
+                info!(target: "cargo", "{}", line);
+            }
+        }
+        process.wait(FailureStage::Bench, Vec::new())?;
+
+        let criterion_dir = target_dir(cargo, benches_dir, envs)?.join("criterion");
+        info!("reading criterion reports from {}", criterion_dir.display());
+        let mut completed = Vec::new();
+        for report in read_criterion_dir(&criterion_dir, Some(started))? {
+            info!("benchmark `{}` complete.", report.id);
+            save_json(
+                output_dir.join(&report.id).with_extension("json"),
+                &report.data,
+                true,
+            )?;
+            completed.push(CompletedBench {
+                timestamp: report.timestamp(),
+                id: report.id,
+            });
+        }
+        if completed.is_empty() {
+            warn!("no criterion report was written by this run");
+        }
+        Ok(completed)
+    }
+}
+
+struct Libtest;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum LibtestEvent {
+    /// Times are in ns/iter. The `deviation` next to the median is the range of the samples
+    /// (max - min), not a confidence interval, and isn't kept.
+    Bench { name: String, median: f64 },
+    #[serde(other)]
+    Other,
+}
+
+impl Harness for Libtest {
+    fn run(
+        &self,
+        cargo: &CargoCommand,
+        benches_dir: &Path,
+        output_dir: &Path,
+        envs: &[(String, String)],
+        filter: Option<&str>,
+    ) -> Result<Vec<CompletedBench>> {
+        // libtest filters are substrings of the test path, so the regex is applied to the
+        // results instead
+        let filter = filter
+            .map(Regex::new)
+            .transpose()
+            .context("invalid benchmark filter")?;
+        let mut cmd = cargo.command(benches_dir, envs);
+        cmd.args(["bench", "--", "-Z", "unstable-options", "--format", "json"]);
+        let mut process = HarnessProcess::spawn(cmd)?;
+
+        let mut completed = Vec::new();
+        for line in process.stdout_lines() {
+            let Some((id, data)) = parse_libtest_line(&line) else {
+                continue;
+            };
+            if filter.as_ref().is_some_and(|f| !f.is_match(&id)) {
+                continue;
+            }
+            info!("benchmark `{}` complete.", id);
+            save_json(output_dir.join(&id).with_extension("json"), &data, true)?;
+            completed.push(CompletedBench {
+                id,
+                timestamp: chrono::Local::now().to_rfc3339(),
+            });
+        }
+
+        process.wait(FailureStage::Bench, Vec::new())?;
+        Ok(completed)
+    }
+}
+
+/// The benchmark id and result of a `bench` event of libtest's JSON output
+fn parse_libtest_line(line: &str) -> Option<(String, BenchResult)> {
+    let Ok(LibtestEvent::Bench { name, median }) = serde_json::from_str::<LibtestEvent>(line)
+    else {
+        return None;
+    };
+    // libtest only reports the median, which stands in for the mean. It has no confidence
+    // interval, and its sample range isn't one: the bounds collapse onto the median and the
+    // result is flagged so nothing compares them.
+    let estimate = Estimate {
+        estimate: median,
+        lower_bound: median,
+        upper_bound: median,
+        unit: "ns".to_string(),
+    };
+    let data = BenchResult {
+        report_directory: None,
+        iteration_count: Vec::new(),
+        measured_values: Vec::new(),
+        unit: "ns".to_string(),
+        throughput: Vec::new(),
+        typical: estimate.clone(),
+        mean: estimate.clone(),
+        median: estimate,
+        median_abs_dev: None,
+        slope: None,
+        change: None,
+        no_interval: true,
+    };
+    Some((name.replace("::", "/"), data))
+}
+
+/// The target directory cargo uses for the workspace of `benches_dir`
+fn target_dir(
+    cargo: &CargoCommand,
+    benches_dir: &Path,
+    envs: &[(String, String)],
+) -> Result<PathBuf> {
+    let output = cargo
+        .command(benches_dir, envs)
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .output()
+        .context("failed to run cargo metadata")?;
+    if !output.status.success() {
+        bail!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let metadata: Value = serde_json::from_slice(&output.stdout)?;
+    metadata["target_directory"]
+        .as_str()
+        .map(PathBuf::from)
+        .context("cargo metadata reported no target_directory")
+}
+
+/// A benchmark read from criterion's on-disk reports
+pub struct CriterionReport {
+    pub id: String,
+    /// When criterion wrote the report
+    pub measured_at: SystemTime,
+    pub data: BenchResult,
+}
+
+impl CriterionReport {
+    /// `measured_at` as recorded in `run.json`
+    pub fn timestamp(&self) -> String {
+        chrono::DateTime::<chrono::Local>::from(self.measured_at).to_rfc3339()
+    }
+}
+
+#[derive(Deserialize)]
+struct RawBenchmark {
+    full_id: String,
+    throughput: Option<Value>,
+}
+
+#[derive(Deserialize)]
+enum RawThroughput {
+    Bytes(u64),
+    BytesDecimal(u64),
+    Elements(u64),
+}
+
+#[derive(Deserialize)]
+struct RawEstimates {
+    mean: RawEstimate,
+    median: RawEstimate,
+    median_abs_dev: RawEstimate,
+    slope: Option<RawEstimate>,
+}
+
+#[derive(Deserialize)]
+struct RawEstimate {
+    confidence_interval: RawConfidenceInterval,
+    point_estimate: f64,
+}
+
+#[derive(Deserialize)]
+struct RawConfidenceInterval {
+    lower_bound: f64,
+    upper_bound: f64,
+}
+
+#[derive(Deserialize)]
+struct RawSample {
+    iters: Vec<f64>,
+    times: Vec<f64>,
+}
+
+impl From<RawEstimate> for Estimate {
+    fn from(raw: RawEstimate) -> Self {
+        // Criterion stores every time in nanoseconds
+        Estimate {
+            estimate: raw.point_estimate,
+            lower_bound: raw.confidence_interval.lower_bound,
+            upper_bound: raw.confidence_interval.upper_bound,
+            unit: "ns".to_string(),
+        }
+    }
+}
+
+/// Read the reports criterion leaves in a `target/criterion` directory. With
+/// `modified_since`, only the benchmarks measured after that time are returned.
+pub fn read_criterion_dir(
+    criterion_dir: &Path,
+    modified_since: Option<SystemTime>,
+) -> Result<Vec<CriterionReport>> {
+    if !criterion_dir.is_dir() {
+        bail!("{} does not exist", criterion_dir.display());
+    }
+    let mut bench_dirs = Vec::new();
+    collect_bench_dirs(criterion_dir, &mut bench_dirs)?;
+    bench_dirs.sort();
+
+    let mut reports = Vec::new();
+    for bench_dir in bench_dirs {
+        let measured_at =
+            std::fs::metadata(bench_dir.join("new").join("estimates.json"))?.modified()?;
+        if modified_since.is_some_and(|since| measured_at < since) {
+            continue;
+        }
+        match read_criterion_report(&bench_dir) {
+            Ok((id, data)) => reports.push(CriterionReport {
+                id,
+                measured_at,
+                data,
+            }),
+            Err(e) => warn!("skipping {}: {:#}", bench_dir.display(), e),
+        }
+    }
+    Ok(reports)
+}
+
+/// Directories holding a `new/estimates.json`, i.e. one benchmark each
+fn collect_bench_dirs(dir: &Path, bench_dirs: &mut Vec<PathBuf>) -> Result<()> {
+    if dir.join("new").join("estimates.json").exists() {
+        bench_dirs.push(dir.to_path_buf());
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        // `report` holds criterion's html output
+        if path.is_dir() && path.file_name().is_some_and(|name| name != "report") {
+            collect_bench_dirs(&path, bench_dirs)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_criterion_report(bench_dir: &Path) -> Result<(String, BenchResult)> {
+    let new_dir = bench_dir.join("new");
+    let benchmark: RawBenchmark = load_json(new_dir.join("benchmark.json"))?;
+    let estimates: RawEstimates = load_json(new_dir.join("estimates.json"))?;
+    let sample: Option<RawSample> = load_json(new_dir.join("sample.json")).ok();
+
+    let mean = Estimate::from(estimates.mean);
+    let slope = estimates.slope.map(Estimate::from);
+    let throughput = benchmark
+        .throughput
+        .and_then(|t| serde_json::from_value::<RawThroughput>(t).ok())
+        .map(|t| match t {
+            RawThroughput::Bytes(n) => ("bytes", n),
+            RawThroughput::BytesDecimal(n) => ("bytes_decimal", n),
+            RawThroughput::Elements(n) => ("elements", n),
+        })
+        .map(|(unit, per_iteration)| Throughput {
+            per_iteration,
+            unit: unit.to_string(),
+        });
+    let (iteration_count, measured_values) = sample
+        .map(|s| (s.iters.into_iter().map(|i| i as u64).collect(), s.times))
+        .unwrap_or_default();
+
+    let data = BenchResult {
+        report_directory: Some(bench_dir.display().to_string()),
+        iteration_count,
+        measured_values,
+        unit: "ns".to_string(),
+        throughput: throughput.into_iter().collect(),
+        // Same as criterion: the slope when it was measured, the mean otherwise
+        typical: slope.clone().unwrap_or_else(|| mean.clone()),
+        mean,
+        median: Estimate::from(estimates.median),
+        median_abs_dev: Some(Estimate::from(estimates.median_abs_dev)),
+        slope,
+        // The verdict needs criterion's significance test, which isn't stored on disk
+        change: None,
+        no_interval: false,
+    };
+    Ok((benchmark.full_id, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn parses_libtest_bench_events() {
+        let line = r#"{ "type": "bench", "name": "render::a", "median": 1200, "deviation": 30 }"#;
+        let (id, data) = parse_libtest_line(line).unwrap();
+        assert_eq!(id, "render/a");
+        assert_eq!(data.mean.estimate, 1200.0);
+        // The sample range isn't a confidence interval
+        assert_eq!(data.mean.lower_bound, 1200.0);
+        assert_eq!(data.mean.upper_bound, 1200.0);
+        assert!(data.no_interval);
+
+        assert!(parse_libtest_line(r#"{ "type": "suite", "event": "started" }"#).is_none());
+        assert!(parse_libtest_line("running 2 tests").is_none());
+    }
+
+    #[test]
+    fn parses_criterion_messages() {
+        let estimate =
+            r#"{"estimate":1200.0,"lower_bound":1190.0,"upper_bound":1210.0,"unit":"ns"}"#;
+        let line = format!(
+            r#"{{"reason":"benchmark-complete","id":"render/a","report_directory":"/target/criterion/reports/render/a","iteration_count":[1,2],"measured_values":[1200.0,2400.0],"unit":"ns","throughput":[],"typical":{e},"mean":{e},"median":{e},"median_abs_dev":{e},"slope":{e},"change":null}}"#,
+            e = estimate
+        );
+        let Some(BenchmarkEvent::BenchmarkComplete(event)) = parse_criterion_line(&line) else {
+            panic!("benchmark-complete not parsed");
+        };
+        assert_eq!(event.id, "render/a");
+        assert_eq!(event.result.mean.estimate, 1200.0);
+        assert_eq!(event.result.mean.lower_bound, 1190.0);
+        assert!(!event.result.no_interval);
+
+        // A known message missing fields is dropped, with a warning
+        assert!(
+            parse_criterion_line(r#"{"reason":"benchmark-complete","id":"render/a"}"#).is_none()
+        );
+        assert!(parse_criterion_line(r#"{"reason":"compiler-artifact"}"#).is_none());
+        assert!(parse_criterion_line("Gnuplot not found").is_none());
+    }
+
+    #[test]
+    fn parses_cargo_build_messages() {
+        let dir = temp_dir("build");
+        let bench = dir.join("render-0123abcd");
+        let rlib = dir.join("libranim-4567ef01.rlib");
+        std::fs::write(&bench, [0; 64]).unwrap();
+        std::fs::write(&rlib, [0; 32]).unwrap();
+        std::fs::write(dir.join("libranim-4567ef01.rmeta"), [0; 8]).unwrap();
+
+        let artifact = |package_id: &str,
+                        name: &str,
+                        kind: &str,
+                        files: &[&Path],
+                        exe: Option<&Path>,
+                        fresh: bool| {
+            serde_json::json!({
+                "reason": "compiler-artifact",
+                "package_id": package_id,
+                "manifest_path": "/src/Cargo.toml",
+                "target": { "kind": [kind], "crate_types": [kind], "name": name, "src_path": "/src/lib.rs" },
+                "profile": {},
+                "features": [],
+                "filenames": files,
+                "executable": exe,
+                "fresh": fresh,
+            })
+            .to_string()
+        };
+        let local = "path+file:///src/ranim#0.1.0";
+        let diagnostic = |level: &str, rendered: &str, spans: usize| {
+            serde_json::json!({
+                "reason": "compiler-message",
+                "package_id": local,
+                "message": { "level": level, "rendered": rendered, "spans": vec![serde_json::json!({}); spans] },
+            })
+            .to_string()
+        };
+
+        let mut output = BuildOutput::default();
+        for line in [
+            artifact(
+                "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.0",
+                "serde",
+                "lib",
+                &[],
+                None,
+                true,
+            ),
+            artifact(
+                local,
+                "ranim",
+                "lib",
+                &[&rlib, &dir.join("libranim-4567ef01.rmeta")],
+                None,
+                false,
+            ),
+            artifact(local, "render", "bench", &[&bench], Some(&bench), false),
+            // The same warning, once for the lib and once for its unit tests
+            diagnostic("warning", "warning: unused variable `x`\n", 1),
+            diagnostic("warning", "warning: unused variable `x`\n", 1),
+            diagnostic("warning", "warning: 1 warning emitted\n", 0),
+            diagnostic(
+                "error",
+                "error[E0425]: cannot find value `y`\n  --> src/lib.rs:1:1\n",
+                1,
+            ),
+            r#"{"reason":"build-finished","success":false}"#.to_string(),
+            "   Compiling ranim v0.1.0".to_string(),
+        ] {
+            output.add_line(&line);
+        }
+
+        assert_eq!((output.fresh, output.compiled), (1, 2));
+        assert_eq!(output.warnings, 1);
+        assert_eq!(
+            output.errors,
+            [
+                "error[E0425]: cannot find value `y`",
+                "  --> src/lib.rs:1:1"
+            ]
+        );
+        assert_eq!(output.metrics.binary_sizes["render"], 64);
+        assert_eq!(output.metrics.lib_sizes.len(), 1);
+        assert_eq!(output.metrics.lib_sizes["ranim.rlib"], 32);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_criterion_reports() {
+        let dir = temp_dir("criterion");
+        let new_dir = dir.join("render").join("a").join("new");
+        std::fs::create_dir_all(&new_dir).unwrap();
+        std::fs::create_dir_all(dir.join("report")).unwrap();
+        let estimate = r#"{"confidence_interval":{"confidence_level":0.95,"lower_bound":990.0,"upper_bound":1010.0},"point_estimate":1000.0,"standard_error":3.0}"#;
+        std::fs::write(
+            new_dir.join("benchmark.json"),
+            r#"{"group_id":"render","function_id":"a","value_str":null,"throughput":{"Elements":10},"full_id":"render/a","directory_name":"render/a","title":"render/a"}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            new_dir.join("estimates.json"),
+            format!(
+                r#"{{"mean":{e},"median":{e},"median_abs_dev":{e},"slope":null,"std_dev":{e}}}"#,
+                e = estimate
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            new_dir.join("sample.json"),
+            r#"{"sampling_mode":"Linear","iters":[1.0,2.0],"times":[1000.0,2000.0]}"#,
+        )
+        .unwrap();
+
+        let reports = read_criterion_dir(&dir, None).unwrap();
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert_eq!(report.id, "render/a");
+        assert_eq!(report.data.mean.lower_bound, 990.0);
+        assert_eq!(report.data.iteration_count, [1, 2]);
+        assert_eq!(report.data.throughput[0].unit, "elements");
+        assert_eq!(report.data.throughput[0].per_iteration, 10);
+        // Without a slope, the typical value is the mean
+        assert_eq!(report.data.typical.estimate, 1000.0);
+
+        // Reports older than the run are left out
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert!(read_criterion_dir(&dir, Some(later)).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+use crate::commands::graph::{add_run, run_dirs, scan_run, CommitDir, RunData};
+use crate::common::{AllData, BenchValue, CommitBenchData, Estimate, RunManifest, METRICS_FILE};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// File name of the index, under db/
+pub const INDEX_FILE: &str = "index.sqlite";
+
+/// Bumped whenever the tables below or the benchmarks derived from a run change, the index is
+/// then rebuilt from scratch
+const SCHEMA_VERSION: i64 = 3;
+
+const SCHEMA: &str = "
+CREATE TABLE runs (
+    commit_hash TEXT NOT NULL,
+    machine TEXT NOT NULL,
+    -- Sizes and mtimes of the run directory and its run.json, failure.json and metrics.json
+    fingerprint TEXT NOT NULL,
+    -- RunManifest, null if the run has no readable run.json
+    manifest TEXT,
+    -- SystemInfo of the run
+    system TEXT,
+    -- FailureRecord of the last failed attempt
+    failure TEXT,
+    PRIMARY KEY (commit_hash, machine)
+);
+CREATE TABLE benchmarks (
+    commit_hash TEXT NOT NULL,
+    machine TEXT NOT NULL,
+    bench_id TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    estimate REAL NOT NULL,
+    lower_bound REAL NOT NULL,
+    upper_bound REAL NOT NULL,
+    median REAL,
+    slope REAL,
+    median_abs_dev REAL,
+    typical REAL,
+    per_second REAL,
+    repeat_std_dev REAL,
+    -- The whole BenchValue
+    value TEXT NOT NULL,
+    PRIMARY KEY (commit_hash, machine, bench_id)
+);
+CREATE INDEX benchmarks_by_id ON benchmarks (bench_id, machine);
+";
+
+/// A SQLite cache of the runs in db/. The JSON files stay the source of truth: the index is
+/// brought up to date with [`Index::refresh`] before being read, and can be deleted at any time.
+pub struct Index {
+    conn: Connection,
+}
+
+/// What a [`Index::refresh`] did
+#[derive(Debug, Default)]
+pub struct RefreshStats {
+    pub runs: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+impl Index {
+    pub fn path(db_root: &Path) -> PathBuf {
+        db_root.join(INDEX_FILE)
+    }
+
+    /// Open the index, creating it if needed
+    pub fn open(db_root: &Path) -> Result<Self> {
+        let path = Self::path(db_root);
+        let conn = Connection::open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            conn.execute_batch(
+                "DROP TABLE IF EXISTS runs;
+                 DROP TABLE IF EXISTS benchmarks;",
+            )?;
+            conn.execute_batch(SCHEMA)?;
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        Ok(Index { conn })
+    }
+
+    /// Open the index only if it was created before
+    pub fn open_existing(db_root: &Path) -> Result<Option<Self>> {
+        if Self::path(db_root).exists() {
+            Self::open(db_root).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Re-read the run directories whose files changed since the last refresh, and drop the
+    /// ones that no longer exist
+    pub fn refresh(&mut self, db_root: &Path) -> Result<RefreshStats> {
+        let mut known: HashMap<(String, String), String> = self
+            .conn
+            .prepare("SELECT commit_hash, machine, fingerprint FROM runs")?
+            .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut stats = RefreshStats::default();
+        let tx = self.conn.transaction()?;
+        for CommitDir { commit_hash, runs } in run_dirs(db_root)? {
+            for (machine, run_path) in runs {
+                stats.runs += 1;
+                let fingerprint = fingerprint(&run_path)?;
+                let key = (commit_hash.clone(), machine.clone());
+                if known.remove(&key).is_some_and(|f| f == fingerprint) {
+                    continue;
+                }
+
+                let run = scan_run(&run_path, &commit_hash, &machine);
+                delete_run(&tx, &commit_hash, &machine)?;
+                insert_run(&tx, &commit_hash, &machine, &fingerprint, &run)?;
+                stats.updated += 1;
+            }
+        }
+        for (commit_hash, machine) in known.keys() {
+            delete_run(&tx, commit_hash, machine)?;
+            stats.removed += 1;
+        }
+        tx.commit()?;
+        Ok(stats)
+    }
+
+    /// Build the same [`AllData`] as scanning db/ would
+    pub fn load_all_data(&self) -> Result<AllData> {
+        let mut runs: HashMap<(String, String), RunData> = HashMap::new();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT commit_hash, machine, manifest, failure FROM runs")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let manifest: Option<String> = row.get(2)?;
+            let failure: Option<String> = row.get(3)?;
+            let run = RunData {
+                manifest: manifest
+                    .map(|m| serde_json::from_str::<RunManifest>(&m))
+                    .transpose()?,
+                failure: failure.map(|f| serde_json::from_str(&f)).transpose()?,
+                benchmarks: HashMap::new(),
+            };
+            runs.insert((row.get(0)?, row.get(1)?), run);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT commit_hash, machine, bench_id, value FROM benchmarks")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let key: (String, String) = (row.get(0)?, row.get(1)?);
+            let value: String = row.get(3)?;
+            if let Some(run) = runs.get_mut(&key) {
+                run.benchmarks
+                    .insert(row.get(2)?, serde_json::from_str(&value)?);
+            }
+        }
+
+        let mut all_data = AllData::default();
+        let mut commits: HashMap<String, CommitBenchData> = HashMap::new();
+        for ((commit_hash, machine), run) in runs {
+            let commit_data = commits.entry(commit_hash).or_default();
+            add_run(&mut all_data, commit_data, machine, run);
+        }
+        all_data.commits = commits
+            .into_iter()
+            .filter(|(_, data)| !data.machines.is_empty() || !data.failures.is_empty())
+            .collect();
+        Ok(all_data)
+    }
+}
+
+fn delete_run(conn: &Connection, commit_hash: &str, machine: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM runs WHERE commit_hash = ?1 AND machine = ?2",
+        params![commit_hash, machine],
+    )?;
+    conn.execute(
+        "DELETE FROM benchmarks WHERE commit_hash = ?1 AND machine = ?2",
+        params![commit_hash, machine],
+    )?;
+    Ok(())
+}
+
+fn insert_run(
+    conn: &Connection,
+    commit_hash: &str,
+    machine: &str,
+    fingerprint: &str,
+    run: &RunData,
+) -> Result<()> {
+    let manifest = run.manifest.as_ref();
+    conn.execute(
+        "INSERT INTO runs (commit_hash, machine, fingerprint, manifest, system, failure)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            commit_hash,
+            machine,
+            fingerprint,
+            manifest.map(serde_json::to_string).transpose()?,
+            manifest
+                .map(|m| serde_json::to_string(&m.system))
+                .transpose()?,
+            run.failure
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?,
+        ],
+    )?;
+
+    let mut stmt = conn.prepare(
+        "INSERT INTO benchmarks (commit_hash, machine, bench_id, unit, estimate, lower_bound,
+             upper_bound, median, slope, median_abs_dev, typical, per_second, repeat_std_dev, value)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+    )?;
+    for (bench_id, value) in &run.benchmarks {
+        let BenchValue {
+            estimate,
+            lower_bound,
+            upper_bound,
+            unit,
+            ..
+        } = value;
+        let point = |e: &Option<Estimate>| e.as_ref().map(|e| e.estimate);
+        stmt.execute(params![
+            commit_hash,
+            machine,
+            bench_id,
+            unit,
+            estimate,
+            lower_bound,
+            upper_bound,
+            point(&value.median),
+            point(&value.slope),
+            point(&value.median_abs_dev),
+            point(&value.typical),
+            value.throughput.as_ref().map(|t| t.per_second),
+            value.repeats.as_ref().map(|r| r.std_dev),
+            serde_json::to_string(value)?,
+        ])?;
+    }
+    Ok(())
+}
+
+/// Size and modification time of the run's top-level files that every write goes through: a
+/// bench or an import moves a freshly written run.json into place, a migration or sync rewrites
+/// it, and a failed attempt writes failure.json. The benchmark files themselves aren't read, so
+/// the refresh doesn't have to walk every run: run `ranim-bench index --rebuild` after editing
+/// them by hand.
+fn fingerprint(dir: &Path) -> Result<String> {
+    let stamp = |path: &Path| -> Result<String> {
+        match std::fs::metadata(path) {
+            Ok(metadata) => {
+                let modified = metadata
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos());
+                Ok(format!("{}@{}", metadata.len(), modified))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok("-".to_string()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    };
+
+    let stamps = [dir.to_path_buf()]
+        .into_iter()
+        .chain(
+            ["run.json", "failure.json", METRICS_FILE]
+                .into_iter()
+                .map(|file| dir.join(file)),
+        )
+        .map(|path| stamp(&path))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(stamps.join(":"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn refreshes_changed_runs_only() {
+        let db_root = temp_dir("index");
+        let run_dir = db_root.join("a".repeat(40)).join("lab");
+        std::fs::create_dir_all(&run_dir).unwrap();
+        std::fs::write(run_dir.join("run.json"), "{}").unwrap();
+
+        let mut index = Index::open(&db_root).unwrap();
+        let stats = index.refresh(&db_root).unwrap();
+        assert_eq!((stats.runs, stats.updated), (1, 1));
+        let stats = index.refresh(&db_root).unwrap();
+        assert_eq!((stats.runs, stats.updated), (1, 0));
+
+        // Staging directories aren't runs
+        std::fs::create_dir_all(db_root.join("a".repeat(40)).join("lab.tmp")).unwrap();
+        std::fs::write(run_dir.join("failure.json"), "{}").unwrap();
+        let stats = index.refresh(&db_root).unwrap();
+        assert_eq!((stats.runs, stats.updated), (1, 1));
+
+        std::fs::remove_dir_all(&run_dir).unwrap();
+        let stats = index.refresh(&db_root).unwrap();
+        assert_eq!((stats.runs, stats.removed), (0, 1));
+
+        drop(index);
+        std::fs::remove_dir_all(&db_root).unwrap();
+    }
+}
@@ -1,12 +1,36 @@
 mod commands {
     pub mod bench;
     pub mod bench_missing;
+    pub mod compare;
+    pub mod export;
     pub mod graph;
+    pub mod import;
+    pub mod index;
+    pub mod list_adapters;
+    pub mod migrate;
+    pub mod query;
+    pub mod regress;
     pub mod sync;
+    pub mod validate;
 }
+mod columnar;
 mod common;
+mod config;
+mod harness;
+mod index;
+mod migrations;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
+use crate::commands::bench::{BenchFilter, BenchOptions};
+use crate::commands::bench_missing::BenchMissingOptions;
+use crate::commands::export::{ExportFormat, ExportOptions, Statistic};
+use crate::commands::import::ImportOptions;
+use crate::commands::query::{QueryFormat, QueryOptions};
+use crate::common::{AdapterSelector, OutputFormat};
+use crate::config::{Config, SelectionConfig, WorkspaceArgs};
+use crate::harness::HarnessKind;
 use crate::utils::run_git;
 use anyhow::{bail, Result};
 use clap::{Parser, Subcommand};
@@ -21,6 +45,8 @@ use tracing_subscriber::EnvFilter;
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(flatten)]
+    workspace: WorkspaceArgs,
     #[command(subcommand)]
     command: Commands,
 }
@@ -32,63 +58,339 @@ enum Commands {
         /// Skip working directory clean check
         #[arg(long)]
         allow_dirty: bool,
-        /// Machine/run name (e.g. "macbookpro", "aorus")
+        /// Machine/run name (e.g. "macbookpro", "aorus") [default: from `ranim-bench.toml`]
         #[arg(long)]
-        name: String,
+        name: Option<String>,
         /// Overwrite existing output directory
         #[arg(long)]
         force: bool,
+        /// Merge the results into the existing output directory instead of replacing it
+        #[arg(long)]
+        merge: bool,
+        /// Run the suite this many times to capture run-to-run variance
+        #[arg(long, default_value_t = 1)]
+        repeat: usize,
+        /// wgpu adapter to benchmark on, by index, name or backend (see `list-adapters`)
+        #[arg(long)]
+        adapter: Option<AdapterSelector>,
+        /// Benchmark harness [default: from `ranim-bench.toml`, else cargo-criterion]
+        #[arg(long, value_enum)]
+        harness: Option<HarnessKind>,
+        #[command(flatten)]
+        filter: BenchFilter,
     },
-    /// Auto-benchmark all PR-merged commits missing data for this machine
+    /// Auto-benchmark all selected commits missing data for this machine (PR-merged commits by
+    /// default, see `ranim-bench.toml` for per-machine policies)
     BenchMissing {
-        /// Machine/run name (e.g. "macbookpro", "aorus")
+        /// Machine/run name (e.g. "macbookpro", "aorus") [default: from `ranim-bench.toml`]
         #[arg(long)]
-        name: String,
+        name: Option<String>,
         /// Overwrite existing benchmark data
         #[arg(long)]
         force: bool,
+        /// Merge the results into existing benchmark data instead of replacing it
+        #[arg(long)]
+        merge: bool,
+        /// Run the suite this many times on each commit to capture run-to-run variance
+        #[arg(long, default_value_t = 1)]
+        repeat: usize,
         /// Only show what would be benchmarked, don't run
         #[arg(long)]
         dry_run: bool,
+        /// Continue the queue of a previously interrupted invocation
+        #[arg(long)]
+        resume: bool,
+        /// Also benchmark commits with a recorded failure.json
+        #[arg(long)]
+        retry_failed: bool,
+        /// Benchmark each commit in a temporary git worktree, leaving the checkout of the
+        /// repository untouched
+        #[arg(long)]
+        worktree: bool,
+        /// wgpu adapter to benchmark on, by index, name or backend (see `list-adapters`)
+        #[arg(long)]
+        adapter: Option<AdapterSelector>,
+        /// Benchmark harness [default: from `ranim-bench.toml`, else cargo-criterion]
+        #[arg(long, value_enum)]
+        harness: Option<HarnessKind>,
+        #[command(flatten)]
+        selection: SelectionConfig,
+        #[command(flatten)]
+        filter: BenchFilter,
+    },
+    /// Import an existing criterion directory (e.g. `ranim/target/criterion`) as a run
+    Import {
+        /// Machine/run name (e.g. "macbookpro", "aorus") [default: from `ranim-bench.toml`]
+        #[arg(long)]
+        name: Option<String>,
+        /// Commit-ish in the repository the results were measured on
+        #[arg(long, default_value = "HEAD")]
+        commit: String,
+        /// Criterion directory to import [default: target/criterion of the repository]
+        #[arg(long)]
+        criterion_dir: Option<PathBuf>,
+        /// Overwrite existing output directory
+        #[arg(long)]
+        force: bool,
+        /// Merge the results into the existing output directory instead of replacing it
+        #[arg(long)]
+        merge: bool,
+        #[command(flatten)]
+        filter: BenchFilter,
     },
     /// Generate git-graph and all-data.json for web
     Graph,
+    /// Export the benchmark results as a flat table (one row per statistic) for pandas/DuckDB
+    Export {
+        /// Output file
+        output: PathBuf,
+        /// Output format [default: from the extension of the output file]
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+        /// Only export this machine/run name (can be repeated)
+        #[arg(long = "name")]
+        names: Vec<String>,
+        /// Only export the commits of this revision range, e.g. `v0.1.0..origin/main`
+        #[arg(long)]
+        range: Option<String>,
+        #[command(flatten)]
+        filter: BenchFilter,
+    },
+    /// Create or refresh the SQLite index of db/, used by the commands reading db/ once it exists
+    Index {
+        /// Rebuild the index from scratch instead of only re-reading the changed runs
+        #[arg(long)]
+        rebuild: bool,
+    },
+    /// Print how benchmarks evolved along the git history
+    Query {
+        /// Benchmark ids to show, as a glob (e.g. `render/static_squares/*`)
+        #[arg(long)]
+        bench: Option<String>,
+        /// Only show this machine/run name (can be repeated)
+        #[arg(long = "machine")]
+        machines: Vec<String>,
+        /// Statistic to show
+        #[arg(long, value_enum, default_value_t)]
+        stat: Statistic,
+        /// Only show the latest N benchmarked commits of each series
+        #[arg(long)]
+        last: Option<usize>,
+        /// Revision range whose history is walked [default: all refs]
+        #[arg(long)]
+        rev: Option<String>,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: QueryFormat,
+    },
     /// Sync run.json files from db structure
     Sync,
+    /// Upgrade the runs in db/ to the current format version
+    Migrate {
+        /// Only show what would be migrated, don't write anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List the wgpu adapters available on this machine
+    ListAdapters,
+    /// Check the files of db/ against the expected schema and layout
+    Validate {
+        /// Output format of the report
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Detect statistically significant shifts along the first-parent history
+    Regress {
+        /// Branch whose first-parent history is walked [default: the tracked branch, origin/main]
+        #[arg(long)]
+        branch: Option<String>,
+        /// Only check this machine/run name
+        #[arg(long)]
+        name: Option<String>,
+        /// Ignore shifts smaller than this many percent
+        #[arg(long, default_value_t = 0.0)]
+        threshold: f64,
+        /// Output format of the report
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Compare the benchmark results of two commits on one machine
+    Compare {
+        /// Base commit-ish in the repository
+        base: String,
+        /// Head commit-ish in the repository
+        head: String,
+        /// Machine/run name (e.g. "macbookpro", "aorus") [default: from `ranim-bench.toml`]
+        #[arg(long)]
+        name: Option<String>,
+        /// Output format of the report
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
 }
 
 fn main() -> Result<()> {
     init_tracing();
 
-    let root_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let repo_dir = root_dir.join("ranim");
-    assert_submodule_initialized(&repo_dir)?;
-
     let cli = Cli::parse();
 
+    let (root_dir, mut config) = Config::find(cli.workspace.config.as_deref())?;
+    if let Some(project) = &cli.workspace.project {
+        config = config.for_project(project)?;
+    }
+    let workspace = config.workspace(&root_dir, &cli.workspace)?;
+    let repo_dir = workspace.repo_dir.as_path();
+    assert_repo_initialized(repo_dir)?;
+
     match cli.command {
         Commands::Bench {
             allow_dirty,
             name,
             force,
+            merge,
+            repeat,
+            adapter,
+            harness,
+            filter,
         } => {
             if !allow_dirty {
-                ensure_clean(&repo_dir)?;
+                ensure_clean(repo_dir)?;
             } else {
                 warn!("allow dirty is true, skipping clean check...");
             }
 
+            let name = config.machine_name(name)?;
             info!("benchmarking run '{}'...", name);
-            commands::bench::run(&repo_dir, &name, force)?;
+            let interrupted = commands::bench::handle_interrupts()?;
+            let options = BenchOptions {
+                force,
+                merge,
+                adapter,
+                filter,
+                repeat,
+                harness: harness.unwrap_or_else(|| config.harness_for(&name)),
+                cargo: config.harness_command_for(&name),
+                env: config.env_for(&name),
+                interrupted: Some(interrupted),
+            };
+            commands::bench::run(&workspace, &name, &options)?;
+        }
+        Commands::Import {
+            name,
+            commit,
+            criterion_dir,
+            force,
+            merge,
+            filter,
+        } => {
+            let options = ImportOptions {
+                commit,
+                criterion_dir,
+                force,
+                merge,
+                filter,
+            };
+            commands::import::run(&workspace, &config.machine_name(name)?, &options)?;
+        }
+        Commands::Graph => commands::graph::run(&workspace)?,
+        Commands::Index { rebuild } => commands::index::run(&workspace.db_root, rebuild)?,
+        Commands::Query {
+            bench,
+            machines,
+            stat,
+            last,
+            rev,
+            format,
+        } => {
+            let options = QueryOptions {
+                bench,
+                machines,
+                stat,
+                last,
+                rev,
+                format,
+            };
+            commands::query::run(&workspace, &options)?;
         }
-        Commands::Graph => commands::graph::run(&root_dir, &repo_dir)?,
-        Commands::Sync => commands::sync::run(&root_dir)?,
+        Commands::Sync => commands::sync::run(&workspace.db_root)?,
+        Commands::Export {
+            output,
+            format,
+            names,
+            range,
+            filter,
+        } => {
+            let options = ExportOptions {
+                output,
+                format,
+                machines: names,
+                filter,
+                range,
+            };
+            commands::export::run(&workspace, &options)?;
+        }
+        Commands::Migrate { dry_run } => commands::migrate::run(&workspace.db_root, dry_run)?,
         Commands::BenchMissing {
             name,
             force,
+            merge,
+            repeat,
             dry_run,
+            resume,
+            retry_failed,
+            worktree,
+            adapter,
+            harness,
+            selection,
+            filter,
+        } => {
+            let name = config.machine_name(name)?;
+            let options = BenchMissingOptions {
+                bench: BenchOptions {
+                    force,
+                    merge,
+                    adapter,
+                    filter,
+                    repeat,
+                    harness: harness.unwrap_or_else(|| config.harness_for(&name)),
+                    cargo: config.harness_command_for(&name),
+                    env: config.env_for(&name),
+                    interrupted: None,
+                },
+                dry_run,
+                resume,
+                retry_failed,
+                selection: selection.or(&config.selection_for(&name)),
+                worktree,
+            };
+            commands::bench_missing::run(&workspace, &name, &options)?;
+        }
+        Commands::ListAdapters => commands::list_adapters::run()?,
+        Commands::Validate { format } => {
+            commands::validate::run(&workspace.db_root, &workspace.projects, format)?
+        }
+        Commands::Regress {
+            branch,
+            name,
+            threshold,
+            format,
+        } => {
+            commands::regress::run(
+                &workspace,
+                branch.as_deref().unwrap_or(&workspace.branch),
+                name.as_deref(),
+                threshold,
+                format,
+            )?;
+        }
+        Commands::Compare {
+            base,
+            head,
+            name,
+            format,
         } => {
-            commands::bench_missing::run(&repo_dir, &name, force, dry_run)?;
+            let name = config.machine_name(name)?;
+            commands::compare::run(&workspace, &base, &head, &name, format)?;
         }
     }
 
@@ -113,13 +415,14 @@ fn init_tracing() {
         .init();
 }
 
-fn assert_submodule_initialized(submodule_dir: &Path) -> Result<()> {
-    let git_file = submodule_dir.join(".git");
+/// Check that `repo_dir` is a git checkout (a clone, worktree or initialized submodule)
+fn assert_repo_initialized(repo_dir: &Path) -> Result<()> {
+    let git_file = repo_dir.join(".git");
     info!("checking if {git_file:?} exists");
     if !git_file.exists() {
         bail!(
-            "子模块 {} 未初始化，请运行 `git submodule update --init --recursive`",
-            submodule_dir.display()
+            "{} 不是 git 仓库；如果是子模块，请运行 `git submodule update --init --recursive`",
+            repo_dir.display()
         );
     }
     Ok(())
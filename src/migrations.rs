@@ -0,0 +1,284 @@
+use crate::common::RunManifest;
+use crate::utils::{bench_ids, load_json};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+
+/// A run directory being upgraded. Migrations work on the raw JSON of the manifest so they
+/// keep working however [`RunManifest`] changes later.
+pub struct RunDir<'a> {
+    pub path: &'a Path,
+    /// The content of `run.json`, if any
+    pub manifest: Option<Value>,
+    /// Files superseded by the migrations, removed once the upgraded manifest is written
+    pub obsolete: Vec<PathBuf>,
+}
+
+impl RunDir<'_> {
+    fn manifest_mut(&mut self) -> Result<&mut Map<String, Value>> {
+        self.manifest
+            .as_mut()
+            .ok_or_else(|| anyhow!("missing run.json"))?
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("run.json is not an object"))
+    }
+}
+
+struct Migration {
+    description: &'static str,
+    /// Returns whether the run was changed
+    apply: fn(&mut RunDir) -> Result<bool>,
+}
+
+/// `MIGRATIONS[i]` upgrades a run from format version `i` to `i + 1`. Runs written before
+/// versioning have no `format_version` and count as version 0.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "adopt legacy system_info.json as run.json",
+        apply: adopt_system_info,
+    },
+    Migration {
+        description: "add per-benchmark records and system snapshots",
+        apply: add_records,
+    },
+];
+
+/// Format version of the runs written by this build
+pub const CURRENT_FORMAT_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// The result of running the pending migrations of a run
+pub struct Upgrade {
+    pub manifest: RunManifest,
+    /// Format version found on disk
+    pub from_version: u32,
+    /// Descriptions of the migrations that changed something
+    pub applied: Vec<&'static str>,
+    pub obsolete: Vec<PathBuf>,
+}
+
+/// Load the manifest of a run, upgraded in memory to [`CURRENT_FORMAT_VERSION`]
+pub fn load_manifest(run_dir: &Path) -> Result<RunManifest> {
+    Ok(upgrade(run_dir)?.manifest)
+}
+
+/// Run the pending migrations of a run, without writing anything
+pub fn upgrade(run_dir: &Path) -> Result<Upgrade> {
+    let manifest_path = run_dir.join("run.json");
+    let mut run = RunDir {
+        path: run_dir,
+        manifest: if manifest_path.exists() {
+            Some(load_json(&manifest_path)?)
+        } else {
+            None
+        },
+        obsolete: Vec::new(),
+    };
+
+    let from_version = run
+        .manifest
+        .as_ref()
+        .and_then(|m| m.get("format_version"))
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u32;
+    if from_version > CURRENT_FORMAT_VERSION {
+        bail!(
+            "run.json has format version {}, this build only knows up to {}",
+            from_version,
+            CURRENT_FORMAT_VERSION
+        );
+    }
+
+    let mut applied = Vec::new();
+    for migration in &MIGRATIONS[from_version as usize..] {
+        let changed = (migration.apply)(&mut run).with_context(|| {
+            format!(
+                "migration `{}` failed for {}",
+                migration.description,
+                run_dir.display()
+            )
+        })?;
+        if changed {
+            applied.push(migration.description);
+        }
+    }
+
+    let manifest = run.manifest_mut()?;
+    manifest.insert("format_version".to_string(), json!(CURRENT_FORMAT_VERSION));
+    let manifest = serde_json::from_value(Value::Object(std::mem::take(manifest)))
+        .with_context(|| format!("failed to parse run.json of {}", run_dir.display()))?;
+    Ok(Upgrade {
+        manifest,
+        from_version,
+        applied,
+        obsolete: run.obsolete,
+    })
+}
+
+/// v0 -> v1: the first runs stored their system info (or a whole manifest) in
+/// `system_info.json`, without a `run.json`
+fn adopt_system_info(run: &mut RunDir) -> Result<bool> {
+    if run.manifest.is_some() {
+        return Ok(false);
+    }
+    let legacy_path = run.path.join("system_info.json");
+    if !legacy_path.exists() {
+        bail!("missing run.json and system_info.json");
+    }
+    let mut manifest: Map<String, Value> = load_json(&legacy_path)?;
+
+    let name = run.path.file_name().map(|n| n.to_string_lossy());
+    let commit_hash = run
+        .path
+        .parent()
+        .and_then(Path::file_name)
+        .map(|n| n.to_string_lossy());
+    let (Some(name), Some(commit_hash)) = (name, commit_hash) else {
+        bail!("{} is not a run directory", run.path.display());
+    };
+    manifest.insert("commit_hash".to_string(), json!(commit_hash));
+    manifest.insert("name".to_string(), json!(name));
+    if !manifest.contains_key("benchmarks") {
+        manifest.insert("benchmarks".to_string(), json!(bench_ids(run.path)?));
+    }
+
+    run.manifest = Some(Value::Object(manifest));
+    run.obsolete.push(legacy_path);
+    Ok(true)
+}
+
+/// v1 -> v2: runs from before `bench --merge` only have the flattened system snapshot, which
+/// becomes `systems[0]` and the snapshot of every benchmark
+fn add_records(run: &mut RunDir) -> Result<bool> {
+    let manifest = run.manifest_mut()?;
+    if manifest
+        .get("systems")
+        .and_then(Value::as_array)
+        .is_some_and(|systems| !systems.is_empty())
+    {
+        return Ok(false);
+    }
+
+    const RUN_FIELDS: &[&str] = &[
+        "format_version",
+        "commit_hash",
+        "name",
+        "benchmarks",
+        "records",
+        "systems",
+    ];
+    let system: Map<String, Value> = manifest
+        .iter()
+        .filter(|(key, _)| !RUN_FIELDS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    let benchmarks: Vec<String> = manifest
+        .get("benchmarks")
+        .cloned()
+        .map(serde_json::from_value)
+        .transpose()?
+        .unwrap_or_default();
+    let records = manifest
+        .entry("records")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("records is not an object"))?;
+    for id in benchmarks {
+        records
+            .entry(id)
+            .or_insert_with(|| json!({ "timestamp": null, "system": 0, "repeats": 1 }));
+    }
+    manifest.insert("systems".to_string(), json!([system]));
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{system, temp_dir};
+
+    const COMMIT: &str = "281ea13db54165e8362f2f5f85169561148eafba";
+
+    /// A run directory `<tmp>/<COMMIT>/<name>` holding the given files
+    fn run_dir(name: &str, files: &[(&str, Value)]) -> PathBuf {
+        let dir = temp_dir(&format!("migrations-{}", name))
+            .join(COMMIT)
+            .join(name);
+        for (path, content) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content.to_string()).unwrap();
+        }
+        dir
+    }
+
+    /// Remove the temp dir of a [`run_dir`]
+    fn remove_run_dir(dir: &Path) {
+        std::fs::remove_dir_all(dir.ancestors().nth(2).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn upgrades_legacy_system_info() {
+        let dir = run_dir(
+            "legacy",
+            &[
+                ("system_info.json", system()),
+                ("render/a.json", json!({})),
+                ("render/group.json", json!({})),
+                ("eval.json", json!({})),
+            ],
+        );
+        let upgrade = upgrade(&dir).unwrap();
+        assert_eq!(upgrade.from_version, 0);
+        assert_eq!(upgrade.applied.len(), MIGRATIONS.len());
+        assert_eq!(upgrade.obsolete, [dir.join("system_info.json")]);
+
+        let manifest = upgrade.manifest;
+        assert_eq!(manifest.format_version, CURRENT_FORMAT_VERSION);
+        assert_eq!(manifest.commit_hash, COMMIT);
+        assert_eq!(manifest.name, "legacy");
+        assert_eq!(manifest.benchmarks, ["eval", "render/a"]);
+        assert_eq!(manifest.systems.len(), 1);
+        assert_eq!(manifest.systems[0].arch, "x86_64");
+        assert_eq!(manifest.records["render/a"].system, 0);
+        assert_eq!(manifest.records["render/a"].repeats, 1);
+        remove_run_dir(&dir);
+    }
+
+    #[test]
+    fn current_runs_are_left_alone() {
+        let mut manifest = system();
+        let fields = json!({
+            "format_version": CURRENT_FORMAT_VERSION,
+            "commit_hash": COMMIT,
+            "name": "current",
+            "benchmarks": ["eval"],
+            "records": { "eval": { "timestamp": null, "system": 0, "repeats": 1 } },
+            "systems": [system()],
+        });
+        manifest
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        let dir = run_dir("current", &[("run.json", manifest)]);
+
+        let upgrade = upgrade(&dir).unwrap();
+        assert_eq!(upgrade.from_version, CURRENT_FORMAT_VERSION);
+        assert!(upgrade.applied.is_empty());
+        assert!(upgrade.obsolete.is_empty());
+        remove_run_dir(&dir);
+    }
+
+    #[test]
+    fn rejects_newer_formats() {
+        let dir = run_dir(
+            "newer",
+            &[(
+                "run.json",
+                json!({ "format_version": CURRENT_FORMAT_VERSION + 1 }),
+            )],
+        );
+        assert!(upgrade(&dir).is_err());
+        remove_run_dir(&dir);
+    }
+}
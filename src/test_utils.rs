@@ -0,0 +1,23 @@
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+/// An empty directory for the test `name`, unique to this process
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("ranim-bench-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The least a [`SystemInfo`](crate::common::SystemInfo) holds
+pub fn system() -> Value {
+    json!({
+        "kernel_version": null,
+        "os_version": "Linux",
+        "distribution_id": "debian",
+        "arch": "x86_64",
+        "memory": 1024,
+        "cpus": [],
+    })
+}
@@ -1,9 +1,11 @@
-use crate::common::{CpuInfo, SystemInfo};
+use crate::common::{AdapterSelector, CpuInfo, SystemInfo, METRICS_FILE, REPEATS_DIR};
 use anyhow::{anyhow, Context, Result};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::path::Path;
 use std::process::{Command, Stdio};
+use tracing::warn;
+use wgpu::AdapterInfo;
 
 pub fn load_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T> {
     let path = path.as_ref();
@@ -11,14 +13,39 @@ pub fn load_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T> {
     Ok(serde_json::from_str(&content)?)
 }
 
-pub fn save_json<T: Serialize>(path: impl AsRef<Path>, data: &T) -> Result<()> {
+/// Write `data` as JSON, pretty-printed for files people read or diff, compact for files only
+/// read by programs
+pub fn save_json<T: Serialize>(path: impl AsRef<Path>, data: &T, pretty: bool) -> Result<()> {
     let path = path.as_ref();
-    if let Some(parent) = path.parent() {
-        if !parent.exists() {
-            std::fs::create_dir_all(parent)?;
+    if let Some(parent) = path.parent()
+        && !parent.exists()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let json = if pretty {
+        serde_json::to_string_pretty(data)?
+    } else {
+        serde_json::to_string(data)?
+    };
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+/// Recursively copy `src` into `dst`, skipping top-level entries named in `exclude`
+pub fn copy_dir_all(src: &Path, dst: &Path, exclude: &[&str]) -> Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        if exclude.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            copy_dir_all(&path, &dst.join(entry.file_name()), &[])?;
+        } else {
+            std::fs::copy(&path, dst.join(entry.file_name()))?;
         }
     }
-    std::fs::write(path, serde_json::to_string_pretty(data)?)?;
     Ok(())
 }
 
@@ -42,10 +69,20 @@ pub fn run_git(repo_dir: &Path, args: impl IntoIterator<Item = impl AsRef<str>>)
             stderr
         ));
     }
-    Ok(String::from_utf8(output.stdout).context("解析 git 输出?UTF-8")?)
+    String::from_utf8(output.stdout).context("解析 git 输出?UTF-8")
 }
 
-pub fn collect_system_info() -> SystemInfo {
+/// Resolve a commit-ish of the repo to its full hash
+pub fn resolve_commit(repo_dir: &Path, rev: &str) -> Result<String> {
+    let hash = run_git(
+        repo_dir,
+        ["rev-parse", "--verify", &format!("{rev}^{{commit}}")],
+    )
+    .with_context(|| format!("failed to resolve `{}`", rev))?;
+    Ok(hash.trim().to_string())
+}
+
+pub fn collect_system_info(selector: Option<&AdapterSelector>) -> Result<SystemInfo> {
     use sysinfo::System;
     let mut sys = System::new_all();
     sys.refresh_all();
@@ -62,20 +99,138 @@ pub fn collect_system_info() -> SystemInfo {
         .collect();
 
     let instance = wgpu::Instance::default();
-    let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-        power_preference: wgpu::PowerPreference::HighPerformance,
-        ..Default::default()
-    }))
-    .expect("failed to request wgpu adapter");
-    let wgpu_adapter_info = adapter.get_info();
+    let wgpu_adapters = enumerate_adapters(&instance);
+
+    let wgpu_adapter_info = match selector {
+        Some(selector) => {
+            let info = wgpu_adapters
+                .iter()
+                .enumerate()
+                .find(|(i, info)| selector.matches(*i, info))
+                .map(|(_, info)| info.clone())
+                .ok_or_else(|| anyhow!("no wgpu adapter matches {:?}", selector))?;
+            Some(info)
+        }
+        None => request_adapter(&instance).map(|adapter| adapter.get_info()),
+    };
+    // A software adapter is what wgpu falls back to without a GPU, however it was picked
+    let wgpu_fallback_adapter = wgpu_adapter_info
+        .as_ref()
+        .is_some_and(|info| info.device_type == wgpu::DeviceType::Cpu);
+    let wgpu_adapter_index = wgpu_adapter_info
+        .as_ref()
+        .and_then(|info| wgpu_adapters.iter().position(|a| a == info));
 
-    SystemInfo {
+    Ok(SystemInfo {
         kernel_version: Some(System::kernel_long_version()),
         os_version: System::long_os_version().unwrap_or_default(),
         distribution_id: System::distribution_id(),
         arch: System::cpu_arch(),
         memory: sys.total_memory(),
         cpus,
+        wgpu_backend: wgpu_adapter_info.as_ref().map(|info| info.backend),
         wgpu_adapter_info,
+        wgpu_fallback_adapter,
+        wgpu_adapters,
+        wgpu_adapter_index,
+    })
+}
+
+/// Enumerate the adapters of all backends available on this machine
+pub fn enumerate_adapters(instance: &wgpu::Instance) -> Vec<AdapterInfo> {
+    instance
+        .enumerate_adapters(wgpu::Backends::all())
+        .iter()
+        .map(|adapter| adapter.get_info())
+        .collect()
+}
+
+/// Request a high performance adapter, falling back to wgpu's software adapter on
+/// headless machines
+fn request_adapter(instance: &wgpu::Instance) -> Option<wgpu::Adapter> {
+    match pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        ..Default::default()
+    })) {
+        Ok(adapter) => return Some(adapter),
+        Err(e) => warn!("failed to request wgpu adapter: {e}, trying fallback adapter"),
+    }
+    match pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+        force_fallback_adapter: true,
+        ..Default::default()
+    })) {
+        Ok(adapter) => Some(adapter),
+        Err(e) => {
+            warn!("no wgpu adapter available: {e}, recording system info without one");
+            None
+        }
+    }
+}
+
+/// The IDs of the benchmark files stored in a run directory, sorted
+pub fn bench_ids(run_dir: &Path) -> Result<Vec<String>> {
+    let mut benchmarks = Vec::new();
+    for entry in std::fs::read_dir(run_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().unwrap();
+
+        if matches!(
+            name.as_str(),
+            "run.json" | "system_info.json" | "failure.json" | METRICS_FILE
+        ) {
+            continue;
+        }
+        if name.ends_with(".json") {
+            let id = name.strip_suffix(".json").unwrap().to_string();
+            benchmarks.push(id);
+        } else if entry.path().is_dir() && name != REPEATS_DIR {
+            collect_bench_ids(&entry.path(), &name, &mut benchmarks)?;
+        }
+    }
+
+    benchmarks.sort();
+    Ok(benchmarks)
+}
+
+fn collect_bench_ids(dir: &Path, prefix: &str, benchmarks: &mut Vec<String>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().unwrap();
+        let path = entry.path();
+
+        if path.is_dir() {
+            collect_bench_ids(&path, &format!("{}/{}", prefix, name), benchmarks)?;
+        } else if name.ends_with(".json") && name != "group.json" {
+            let id_part = name.strip_suffix(".json").unwrap();
+            benchmarks.push(format!("{}/{}", prefix, id_part));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn bench_ids_skip_run_files() {
+        let run_dir = temp_dir("ids");
+        std::fs::create_dir_all(run_dir.join("render")).unwrap();
+        std::fs::create_dir_all(run_dir.join(REPEATS_DIR).join("1")).unwrap();
+        for file in [
+            "run.json",
+            "failure.json",
+            METRICS_FILE,
+            "eval.json",
+            "render/a.json",
+            "repeats/1/eval.json",
+        ] {
+            std::fs::write(run_dir.join(file), "{}").unwrap();
+        }
+
+        assert_eq!(bench_ids(&run_dir).unwrap(), ["eval", "render/a"]);
+
+        std::fs::remove_dir_all(&run_dir).unwrap();
     }
 }
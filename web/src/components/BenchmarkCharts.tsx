@@ -44,6 +44,11 @@ export function BenchmarkCharts({
                   if (val > 1000) return `${(val / 1000).toFixed(1)}µs`;
                   return `${val.toFixed(0)}ns`;
                 }
+                if (unit === 'B') {
+                  if (val > 1024 * 1024) return `${(val / 1024 / 1024).toFixed(1)}MiB`;
+                  if (val > 1024) return `${(val / 1024).toFixed(1)}KiB`;
+                  return `${val.toFixed(0)}B`;
+                }
                 return val.toFixed(2);
               };
 
@@ -34,8 +34,14 @@ export function SystemInfoView({ selectedMachines, getSystemInfo }: SystemInfoVi
               </div>
               <div>
                 <h4 className="font-semibold text-gray-500 mb-1">GPU (WGPU)</h4>
-                <p>{info.wgpu_adapter_info.name}</p>
-                <p className="text-xs text-gray-400">{info.wgpu_adapter_info.backend} - {info.wgpu_adapter_info.driver}</p>
+                {info.wgpu_adapter_info ? (
+                  <>
+                    <p>{info.wgpu_adapter_info.name}{info.wgpu_fallback_adapter && ' (fallback)'}</p>
+                    <p className="text-xs text-gray-400">{info.wgpu_adapter_info.backend} - {info.wgpu_adapter_info.driver}</p>
+                  </>
+                ) : (
+                  <p className="text-gray-400 italic">No adapter (headless)</p>
+                )}
               </div>
             </div>
           </div>
@@ -34,20 +34,106 @@ export interface SystemInfo {
   arch: string;
   memory: number;
   cpus: CpuInfo[];
-  wgpu_adapter_info: AdapterInfo;
+  wgpu_adapter_info: AdapterInfo | null;
+  wgpu_backend: string | null;
+  wgpu_fallback_adapter: boolean;
+  wgpu_adapters: AdapterInfo[];
+  wgpu_adapter_index: number | null;
+}
+
+export interface Estimate {
+  estimate: number;
+  lower_bound: number;
+  upper_bound: number;
+  unit: string;
+}
+
+export type ChangeVerdict = 'NoChange' | 'Improved' | 'Regressed';
+
+export interface ChangeEstimates {
+  mean: Estimate;
+  median: Estimate;
+  change: ChangeVerdict;
 }
 
 export interface BenchValue {
   estimate: number;
+  lower_bound: number;
+  upper_bound: number;
+  // The bounds only repeat the estimate (libtest)
+  no_interval: boolean;
+  unit: string;
+  median: Estimate | null;
+  slope: Estimate | null;
+  median_abs_dev: Estimate | null;
+  typical: Estimate | null;
+  change: ChangeEstimates | null;
+  throughput: ThroughputValue | null;
+  repeats: RepeatStats | null;
+}
+
+export interface RepeatStats {
+  count: number;
+  estimate: number;
+  std_dev: number;
+  min: number;
+  max: number;
+}
+
+export interface ThroughputValue {
+  per_iteration: number;
   unit: string;
+  time_per_element: number;
+  per_second: number;
+}
+
+export interface FailureRecord {
+  commit_hash: string;
+  name: string;
+  stage: 'checkout' | 'build' | 'bench';
+  exit_code: number | null;
+  error: string;
+  stderr_tail: string[];
+  timestamp: string;
 }
 
 export interface CommitBenchData {
   machines: string[];
   benchmarks: Record<string, Record<string, BenchValue>>;
+  failures: Record<string, FailureRecord>;
 }
 
 export interface AllData {
   machines: Record<string, SystemInfo>;
   commits: Record<string, CommitBenchData>;
 }
+
+// Columnar export in data/: series refer to commits, machines and benchmarks by their
+// index in the manifest tables
+
+export interface ColumnarManifest {
+  format_version: number;
+  commits: string[];
+  machines: string[];
+  benchmarks: string[];
+  systems: (SystemInfo | null)[];
+  failures: { commit: number; machine: number; record: FailureRecord }[];
+  all: string;
+  shards: { benchmark: number; path: string; points: number }[];
+}
+
+export interface Series {
+  benchmark: number;
+  machine: number;
+  unit: string;
+  commits: number[];
+  estimate: number[];
+  lower_bound: number[];
+  upper_bound: number[];
+  per_second?: (number | null)[];
+  repeat_std_dev?: (number | null)[];
+}
+
+export interface SeriesFile {
+  series: Series[];
+}